@@ -0,0 +1,12 @@
+CREATE TABLE password_reset_requests (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    email TEXT NOT NULL,
+    ip_address TEXT,
+    created_at TEXT DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_password_reset_requests_email ON password_reset_requests (email, created_at);
+CREATE INDEX idx_password_reset_requests_ip ON password_reset_requests (ip_address, created_at);
+
+-- Tokens used to be stored in plain text, invalidate them
+UPDATE users SET reset_token = NULL, reset_token_expires = NULL;
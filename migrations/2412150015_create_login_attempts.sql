@@ -0,0 +1,8 @@
+-- Failed logins per key ("account:<email>" or "ip:<address>"). Times are
+-- unix seconds so the in-memory store can share the same logic.
+CREATE TABLE login_attempts (
+    key TEXT PRIMARY KEY,
+    failures INTEGER NOT NULL,
+    last_failure_at INTEGER NOT NULL,
+    blocked_until INTEGER
+);
@@ -0,0 +1,37 @@
+-- SQLite can't alter a CHECK constraint, so the users table is rebuilt.
+-- Migrations run with foreign keys off, so dropping the old table leaves
+-- the rows that reference it alone.
+CREATE TABLE users_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    email TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    full_name TEXT NOT NULL,
+    phone TEXT,
+    role TEXT NOT NULL CHECK (role IN ('admin', 'seller', 'buyer', 'owner', 'tenant', 'agent')),
+    verified BOOLEAN DEFAULT FALSE,
+    profile_image_url TEXT,
+    created_at TEXT DEFAULT CURRENT_TIMESTAMP,
+    updated_at TEXT DEFAULT CURRENT_TIMESTAMP,
+    reset_token TEXT,
+    reset_token_expires DATETIME
+);
+
+INSERT INTO users_new SELECT * FROM users;
+DROP TABLE users;
+ALTER TABLE users_new RENAME TO users;
+
+CREATE TABLE role_permissions (
+    role TEXT NOT NULL,
+    permission TEXT NOT NULL,
+    PRIMARY KEY (role, permission)
+);
+
+INSERT INTO role_permissions (role, permission) VALUES
+    ('admin', 'user:list'),
+    ('admin', 'user:unlock'),
+    ('admin', 'user:update_role'),
+    ('admin', 'mfa_policy:manage'),
+    ('admin', 'property:create'),
+    ('seller', 'property:create'),
+    ('owner', 'property:create'),
+    ('agent', 'property:create');
@@ -0,0 +1,25 @@
+-- No foreign keys: events have to outlive the users and rows they mention
+CREATE TABLE audit_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    -- Who did it; NULL for anonymous requests such as a failed login
+    actor_id INTEGER,
+    -- The user the actor was impersonating, if any
+    acting_as_id INTEGER,
+    action TEXT NOT NULL,
+    target_type TEXT,
+    target_id TEXT,
+    ip_address TEXT,
+    user_agent TEXT,
+    -- JSON snapshots of the target before and after the change
+    before TEXT,
+    after TEXT,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_audit_events_created_at ON audit_events (created_at);
+CREATE INDEX idx_audit_events_actor_id ON audit_events (actor_id);
+CREATE INDEX idx_audit_events_action ON audit_events (action);
+CREATE INDEX idx_audit_events_target ON audit_events (target_type, target_id);
+
+INSERT INTO role_permissions (role, permission) VALUES
+    ('admin', 'audit:read');
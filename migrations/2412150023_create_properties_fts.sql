@@ -0,0 +1,29 @@
+-- Full-text index over the searchable listing text. It stores no copy of
+-- the text, the triggers keep it in step with properties.
+CREATE VIRTUAL TABLE properties_fts USING fts5(
+    title,
+    description,
+    location,
+    content = 'properties',
+    content_rowid = 'id',
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+INSERT INTO properties_fts (properties_fts) VALUES ('rebuild');
+
+CREATE TRIGGER properties_fts_insert AFTER INSERT ON properties BEGIN
+    INSERT INTO properties_fts (rowid, title, description, location)
+    VALUES (new.id, new.title, new.description, new.location);
+END;
+
+CREATE TRIGGER properties_fts_delete AFTER DELETE ON properties BEGIN
+    INSERT INTO properties_fts (properties_fts, rowid, title, description, location)
+    VALUES ('delete', old.id, old.title, old.description, old.location);
+END;
+
+CREATE TRIGGER properties_fts_update AFTER UPDATE OF title, description, location ON properties BEGIN
+    INSERT INTO properties_fts (properties_fts, rowid, title, description, location)
+    VALUES ('delete', old.id, old.title, old.description, old.location);
+    INSERT INTO properties_fts (rowid, title, description, location)
+    VALUES (new.id, new.title, new.description, new.location);
+END;
@@ -0,0 +1,11 @@
+-- WGS84 degrees. geo_x/y/z hold the same point as a unit vector, set by
+-- the application because plain SQLite has no trig functions; distance
+-- queries only need their dot product with the search center.
+ALTER TABLE properties ADD COLUMN latitude REAL CHECK (latitude BETWEEN -90 AND 90);
+ALTER TABLE properties ADD COLUMN longitude REAL CHECK (longitude BETWEEN -180 AND 180);
+ALTER TABLE properties ADD COLUMN geo_x REAL;
+ALTER TABLE properties ADD COLUMN geo_y REAL;
+ALTER TABLE properties ADD COLUMN geo_z REAL;
+
+-- Bounding-box prefilter for radius and map searches
+CREATE INDEX idx_properties_coordinates ON properties (latitude, longitude);
@@ -0,0 +1,7 @@
+-- version backs the ETag used for optimistic concurrency; every write
+-- bumps it. Deleted listings keep their row until restored.
+ALTER TABLE properties ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE properties ADD COLUMN deleted_at TEXT;
+
+INSERT INTO role_permissions (role, permission) VALUES
+    ('admin', 'property:manage');
@@ -0,0 +1,25 @@
+-- Addresses are stored lowercase and matched case-insensitively. Older
+-- mixed-case rows are lowercased unless that collides with another account.
+UPDATE users
+SET email = lower(email)
+WHERE email != lower(email)
+  AND NOT EXISTS (
+      SELECT 1 FROM users other
+      WHERE other.id != users.id AND lower(other.email) = lower(users.email)
+  );
+
+-- Accounts whose addresses differ only in case have to be merged or renamed
+-- by hand before the index can be built. Find them with
+--   SELECT lower(email), group_concat(id) FROM users
+--   GROUP BY lower(email) HAVING count(*) > 1;
+-- This check stops the migration with that hint rather than a bare
+-- unique constraint error.
+CREATE TEMP TABLE email_case_duplicates (
+    accounts INTEGER
+        CONSTRAINT merge_accounts_whose_emails_differ_only_in_case CHECK (accounts = 0)
+);
+INSERT INTO email_case_duplicates
+SELECT count(*) FROM (SELECT 1 FROM users GROUP BY lower(email) HAVING count(*) > 1);
+DROP TABLE email_case_duplicates;
+
+CREATE UNIQUE INDEX idx_users_email_lower ON users (lower(email));
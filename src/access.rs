@@ -0,0 +1,142 @@
+// Resource level authorization: who may read a user, join a conversation
+// or act on a listing
+
+use sqlx::SqlitePool;
+
+use crate::auth::{has_permission, AuthUser, Permission};
+use crate::error::ApiError;
+use crate::models::UserRole;
+
+// Profiles are private to their owner unless the caller's role can read
+// any user
+pub async fn require_self_or(
+    pool: &SqlitePool,
+    auth_user: &AuthUser,
+    user_id: i64,
+    permission: Permission,
+) -> Result<(), ApiError> {
+    if auth_user.user_id == user_id || has_permission(pool, auth_user.user_id, permission).await? {
+        return Ok(());
+    }
+
+    Err(ApiError::AuthorizationError(
+        "Not allowed to access another user's data".to_string(),
+    ))
+}
+
+pub async fn require_participant(
+    pool: &SqlitePool,
+    conversation_id: i64,
+    user_id: i64,
+) -> Result<(), ApiError> {
+    let participant = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1
+            FROM conversation_participants
+            WHERE conversation_id = ? AND user_id = ?
+        ) as "participant: bool"
+        "#,
+        conversation_id,
+        user_id
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    if !participant {
+        return Err(ApiError::AuthorizationError(
+            "Not a participant of this conversation".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+// The people responsible for a listing
+pub struct PropertyParties {
+    pub owner_id: i64,
+    pub agent_id: Option<i64>,
+}
+
+impl PropertyParties {
+    pub fn is_manager(&self, user_id: i64) -> bool {
+        self.owner_id == user_id || self.agent_id == Some(user_id)
+    }
+}
+
+pub async fn property_parties(
+    pool: &SqlitePool,
+    property_id: i64,
+) -> Result<PropertyParties, ApiError> {
+    sqlx::query_as!(
+        PropertyParties,
+        "SELECT owner_id, agent_id FROM properties WHERE id = ? AND deleted_at IS NULL",
+        property_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .ok_or(ApiError::NotFound)
+}
+
+// A listing's agent becomes one of its parties, so it must name an actual
+// agent account
+pub async fn require_agent(pool: &SqlitePool, agent_id: i64) -> Result<(), ApiError> {
+    let role = sqlx::query_scalar!(
+        r#"SELECT role as "role: UserRole" FROM users WHERE id = ?"#,
+        agent_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    if role != Some(UserRole::Agent) {
+        return Err(ApiError::ValidationError(
+            "agent_id must be the id of an agent".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+// How the caller may change a listing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PropertyAccess {
+    Owner,
+    Agent,
+    // Any listing, through property:manage
+    Manager,
+}
+
+impl PropertyAccess {
+    // Agents edit a listing but don't choose who represents it
+    pub fn may_reassign(self) -> bool {
+        self != PropertyAccess::Agent
+    }
+}
+
+// API keys need the property:create scope for their own listings and
+// property:manage for anyone else's
+pub async fn property_access(
+    pool: &SqlitePool,
+    auth_user: &AuthUser,
+    parties: &PropertyParties,
+) -> Result<PropertyAccess, ApiError> {
+    if parties.is_manager(auth_user.user_id) && auth_user.in_scope(Permission::PropertyCreate) {
+        return Ok(if parties.owner_id == auth_user.user_id {
+            PropertyAccess::Owner
+        } else {
+            PropertyAccess::Agent
+        });
+    }
+    if auth_user.in_scope(Permission::PropertyManage)
+        && has_permission(pool, auth_user.user_id, Permission::PropertyManage).await?
+    {
+        return Ok(PropertyAccess::Manager);
+    }
+
+    Err(ApiError::AuthorizationError(
+        "Not allowed to change this listing".to_string(),
+    ))
+}
@@ -0,0 +1,64 @@
+// Personal API keys for scripts, sent as bearer tokens in place of a JWT
+
+use sqlx::SqlitePool;
+
+use crate::error::ApiError;
+use crate::tokens::{hash_token, new_token};
+
+// Lets the extractor tell keys from JWTs and makes leaked keys easy to
+// spot in code and logs
+pub const KEY_PREFIX: &str = "yre_";
+const DISPLAY_PREFIX_LEN: usize = KEY_PREFIX.len() + 8;
+
+pub fn is_api_key(token: &str) -> bool {
+    token.starts_with(KEY_PREFIX)
+}
+
+// Returns the key and the part of it that is shown in listings
+pub fn generate() -> (String, String) {
+    let key = format!("{KEY_PREFIX}{}", new_token());
+    let prefix = key[..DISPLAY_PREFIX_LEN].to_string();
+    (key, prefix)
+}
+
+pub struct KeyOwner {
+    pub key_id: i64,
+    pub user_id: i64,
+    pub scopes: String,
+}
+
+// Resolves a live key to its owner and records that it was used
+pub async fn authenticate(pool: &SqlitePool, key: &str) -> Result<Option<KeyOwner>, ApiError> {
+    let key_hash = hash_token(key);
+
+    let owner = sqlx::query_as!(
+        KeyOwner,
+        r#"
+        SELECT k.id as "key_id!", k.user_id, k.scopes
+        FROM api_keys k
+        JOIN users u ON u.id = k.user_id
+        WHERE k.key_hash = ? AND k.revoked_at IS NULL AND k.expires_at > CURRENT_TIMESTAMP
+        "#,
+        key_hash
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    if let Some(owner) = &owner {
+        // Same one-minute granularity as sessions
+        sqlx::query!(
+            r#"
+            UPDATE api_keys
+            SET last_used_at = CURRENT_TIMESTAMP
+            WHERE id = ? AND (last_used_at IS NULL OR last_used_at < datetime('now', '-1 minute'))
+            "#,
+            owner.key_id
+        )
+        .execute(pool)
+        .await
+        .map_err(ApiError::DatabaseError)?;
+    }
+
+    Ok(owner)
+}
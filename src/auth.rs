@@ -4,7 +4,7 @@ use axum::{
     async_trait,
     body::Body,
     extract::FromRequestParts,
-    extract::State,
+    extract::{FromRef, State},
     http::request::Parts,
     middleware::Next,
     response::Response, // Use axum::response::Response instead of http::Response
@@ -12,150 +12,620 @@ use axum::{
 use axum_extra::headers::{authorization::Bearer, Authorization};
 use axum_extra::TypedHeader;
 
+use crate::api_keys;
+use crate::audit::AuditEvent;
+use crate::client_info::ClientInfo;
+use crate::cookies;
 use crate::error::ApiError;
-use crate::models::User;
+use crate::keyring::KeyRing;
+use crate::mfa;
+use crate::models::{User, UserRole};
+use crate::state::AppState;
 use http::Request;
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{SqliteExecutor, SqlitePool};
 use time::{Duration, OffsetDateTime};
+use uuid::Uuid;
 
-pub const JWT_SECRET: &[u8] = b"your-secret-key"; // In production, use env variable
+// Access and refresh tokens are signed with the same keys, so every token
+// says what it is and each code path checks it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenType {
+    Access,
+    Refresh,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: i64, // user id
     pub exp: i64, // expiration timestamp
     pub iat: i64, // issued at timestamp
-    pub role: String,
+    pub role: UserRole,
+    pub typ: TokenType,
+    pub sid: String, // sessions.id
+    // Set when an admin acts as this user (RFC 8693 actor claim)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Actor {
+    pub sub: i64, // user id of the admin
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshClaims {
+    pub sub: i64, // user id
+    pub exp: i64, // expiration timestamp
+    pub iat: i64, // issued at timestamp
+    pub typ: TokenType,
+    pub jti: String, // refresh_tokens.id
+    pub fam: String, // refresh_tokens.family_id, which is also sessions.id
+}
+
+pub const ACCESS_TOKEN_LIFETIME: Duration = Duration::hours(24);
+pub const REFRESH_TOKEN_LIFETIME: Duration = Duration::days(30);
+
+// The tokens are left out in cookie mode, see cookies.rs
 #[derive(Debug, Serialize)]
 pub struct AuthResponse {
+    #[serde(skip_serializing_if = "String::is_empty")]
     pub token: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
     pub refresh_token: String,
     pub user: User,
 }
 
-pub fn create_token(user: &User) -> Result<String, ApiError> {
+// Returned by login instead of tokens when the account needs a second
+// factor; the challenge token is exchanged at /api/login/mfa
+#[derive(Debug, Serialize)]
+pub struct MfaChallenge {
+    pub mfa_required: bool,
+    pub enrollment_required: bool,
+    pub challenge_token: String,
+}
+
+// Recovery codes are included when the login also completed an enrollment
+#[derive(Debug, Serialize)]
+pub struct MfaLoginResponse {
+    #[serde(flatten)]
+    pub auth: AuthResponse,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery_codes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Authenticated(Box<AuthResponse>),
+    MfaRequired(MfaChallenge),
+}
+
+pub fn create_token(keys: &KeyRing, user: &User, session_id: &str) -> Result<String, ApiError> {
     let now = OffsetDateTime::now_utc();
-    let expiry = now + Duration::hours(24);
+    let expiry = now + ACCESS_TOKEN_LIFETIME;
 
     let claims = Claims {
         sub: user.id.unwrap(),
         exp: expiry.unix_timestamp(),
         iat: now.unix_timestamp(),
-        role: user.role.to_string(),
+        role: user.role,
+        typ: TokenType::Access,
+        sid: session_id.to_string(),
+        act: None,
     };
 
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(JWT_SECRET),
-    )
-    .map_err(|_| ApiError::ValidationError("Token creation failed".to_string()))
+    keys.encode(&claims)
+        .map_err(|_| ApiError::ValidationError("Token creation failed".to_string()))
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImpersonationResponse {
+    pub token: String,
+    pub expires_at: i64,
+    pub user: User,
+}
+
+// A short-lived access token for `user` that names the admin as actor. It
+// hangs off the admin's session, so it can't be refreshed and ends when the
+// admin logs out.
+pub fn create_impersonation_token(
+    keys: &KeyRing,
+    user: User,
+    actor_id: i64,
+    actor_session_id: &str,
+    minutes: i64,
+) -> Result<ImpersonationResponse, ApiError> {
+    let now = OffsetDateTime::now_utc();
+    let expiry = now + Duration::minutes(minutes);
+
+    let claims = Claims {
+        sub: user.id.unwrap(),
+        exp: expiry.unix_timestamp(),
+        iat: now.unix_timestamp(),
+        role: user.role,
+        typ: TokenType::Access,
+        sid: actor_session_id.to_string(),
+        act: Some(Actor { sub: actor_id }),
+    };
+
+    let token = keys
+        .encode(&claims)
+        .map_err(|_| ApiError::ValidationError("Token creation failed".to_string()))?;
+
+    Ok(ImpersonationResponse {
+        token,
+        expires_at: expiry.unix_timestamp(),
+        user,
+    })
+}
+
+// How the request authenticated
+#[derive(Debug)]
+pub enum Credential {
+    // Access token of a login session
+    Session(String),
+    // Personal API key limited to the listed permissions
+    ApiKey { scopes: Vec<String> },
+    // Admin acting as the user, on the admin's session
+    Impersonation { actor_id: i64 },
 }
 
 #[derive(Debug)]
 pub struct AuthUser {
     pub user_id: i64,
-    pub role: String,
+    pub credential: Credential,
+}
+
+impl AuthUser {
+    // Sessions, credentials, MFA and API keys can only be managed from an
+    // interactive login, so a leaked key can't take over the account
+    pub fn require_session(&self) -> Result<&str, ApiError> {
+        match &self.credential {
+            Credential::Session(session_id) => Ok(session_id),
+            Credential::ApiKey { .. } => Err(ApiError::AuthorizationError(
+                "This action is not available with an API key".to_string(),
+            )),
+            Credential::Impersonation { .. } => Err(ApiError::AuthorizationError(
+                "This action is not available while impersonating".to_string(),
+            )),
+        }
+    }
+
+    // Who is really making the request: the admin while impersonating,
+    // otherwise the user
+    pub fn actor_id(&self) -> i64 {
+        match self.credential {
+            Credential::Impersonation { actor_id } => actor_id,
+            _ => self.user_id,
+        }
+    }
+
+    // Whether the credential may use the permission at all, on top of what
+    // the role grants
+    pub fn in_scope(&self, permission: Permission) -> bool {
+        match &self.credential {
+            Credential::Session(_) | Credential::Impersonation { .. } => true,
+            Credential::ApiKey { scopes, .. } => {
+                scopes.iter().any(|scope| scope == permission.as_str())
+            }
+        }
+    }
 }
 
+// Set by `require_permission` once the API key's scopes were checked
+#[derive(Clone, Copy)]
+struct ScopeChecked;
+
+// API keys are only accepted where a scope was checked, i.e. behind
+// `require_permission`. Everywhere else they are refused, so a key can't
+// do more than its scopes say.
 #[async_trait]
 impl<S> FromRequestParts<S> for AuthUser
 where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let AnyCredential(auth_user) = AnyCredential::from_request_parts(parts, state).await?;
+
+        if matches!(auth_user.credential, Credential::ApiKey { .. })
+            && parts.extensions.get::<ScopeChecked>().is_none()
+        {
+            return Err(ApiError::AuthorizationError(
+                "This action is not available with an API key".to_string(),
+            ));
+        }
+
+        Ok(auth_user)
+    }
+}
+
+// Accepts API keys on any route. Only for handlers that check the key's
+// scopes themselves with `in_scope`.
+pub struct AnyCredential(pub AuthUser);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AnyCredential
+where
+    AppState: FromRef<S>,
     S: Send + Sync,
 {
     type Rejection = ApiError;
 
-    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        // Extract the token using axum's typed headers
-        let TypedHeader(Authorization(bearer)) =
-            TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, _state)
-                .await
-                .map_err(|_| {
-                    ApiError::AuthenticationError("Invalid authorization header".to_string())
-                })?;
-
-        // Decode and validate the token
-        let token_data = decode::<Claims>(
-            bearer.token(),
-            &DecodingKey::from_secret(JWT_SECRET),
-            &Validation::default(),
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let app = AppState::from_ref(state);
+
+        // A bearer token wins over the session cookie
+        let bearer = TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
+            .await
+            .ok()
+            .map(|TypedHeader(Authorization(bearer))| bearer.token().to_string());
+        let token = match bearer {
+            Some(token) => token,
+            None => cookies::access_token(&app.config, parts)?.ok_or_else(|| {
+                ApiError::AuthenticationError("Invalid authorization header".to_string())
+            })?,
+        };
+
+        if api_keys::is_api_key(&token) {
+            let owner = api_keys::authenticate(&app.pool, &token)
+                .await?
+                .ok_or_else(|| ApiError::AuthenticationError("Invalid API key".to_string()))?;
+
+            return Ok(AnyCredential(AuthUser {
+                user_id: owner.user_id,
+                credential: Credential::ApiKey {
+                    scopes: owner
+                        .scopes
+                        .split_whitespace()
+                        .map(str::to_string)
+                        .collect(),
+                },
+            }));
+        }
+
+        // Decode and validate the token against the key named by its kid
+        let claims = app
+            .keys
+            .decode::<Claims>(&token)
+            .ok()
+            .filter(|claims| claims.typ == TokenType::Access)
+            .ok_or_else(|| ApiError::AuthenticationError("Invalid token".to_string()))?;
+
+        // Tokens die with their session, even before they expire. An
+        // impersonation token lives on the admin's session.
+        let session_owner = claims.act.as_ref().map_or(claims.sub, |actor| actor.sub);
+        let session = sqlx::query!(
+            r#"
+            SELECT revoked_at
+            FROM sessions
+            WHERE id = ? AND user_id = ?
+            "#,
+            claims.sid,
+            session_owner
         )
-        .map_err(|_| ApiError::AuthenticationError("Invalid token".to_string()))?;
+        .fetch_optional(&app.pool)
+        .await
+        .map_err(ApiError::DatabaseError)?;
+
+        if !matches!(session, Some(ref session) if session.revoked_at.is_none()) {
+            return Err(ApiError::AuthenticationError(
+                "Session has been revoked".to_string(),
+            ));
+        }
 
-        Ok(AuthUser {
-            user_id: token_data.claims.sub,
-            role: token_data.claims.role,
-        })
+        // Only touch last_used_at once a minute to keep writes down
+        sqlx::query!(
+            r#"
+            UPDATE sessions
+            SET last_used_at = CURRENT_TIMESTAMP
+            WHERE id = ? AND last_used_at < datetime('now', '-1 minute')
+            "#,
+            claims.sid
+        )
+        .execute(&app.pool)
+        .await
+        .map_err(ApiError::DatabaseError)?;
+
+        let auth_user = AuthUser {
+            user_id: claims.sub,
+            credential: match claims.act {
+                Some(actor) => Credential::Impersonation {
+                    actor_id: actor.sub,
+                },
+                None => Credential::Session(claims.sid),
+            },
+        };
+
+        if auth_user.actor_id() != auth_user.user_id {
+            tracing::info!(
+                target: "audit",
+                actor_id = auth_user.actor_id(),
+                user_id = auth_user.user_id,
+                method = %parts.method,
+                path = %parts.uri.path(),
+                "request under impersonation"
+            );
+        }
+
+        Ok(AnyCredential(auth_user))
     }
 }
 
-// Add to existing auth.rs
-#[derive(Debug, Clone, PartialEq)]
-pub enum Role {
-    Admin,
-    Agent,
-    User,
+// What a role may do. Roles map to permissions in the role_permissions
+// table, so granting a permission to another role needs no code change.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    UserRead,
+    UserList,
+    UserUnlock,
+    UserUpdateRole,
+    UserImpersonate,
+    MfaPolicyManage,
+    PropertyCreate,
+    PropertyManage,
+    AuditRead,
 }
 
-impl Role {
-    pub fn from_str(role: &str) -> Option<Self> {
-        match role.to_lowercase().as_str() {
-            "admin" => Some(Role::Admin),
-            "agent" => Some(Role::Agent),
-            "user" => Some(Role::User),
-            _ => None,
+impl Permission {
+    pub const ALL: [Permission; 9] = [
+        Permission::UserRead,
+        Permission::UserList,
+        Permission::UserUnlock,
+        Permission::UserUpdateRole,
+        Permission::UserImpersonate,
+        Permission::MfaPolicyManage,
+        Permission::PropertyCreate,
+        Permission::PropertyManage,
+        Permission::AuditRead,
+    ];
+
+    pub fn parse(value: &str) -> Option<Permission> {
+        Permission::ALL
+            .into_iter()
+            .find(|permission| permission.as_str() == value)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::UserRead => "user:read",
+            Permission::UserList => "user:list",
+            Permission::UserUnlock => "user:unlock",
+            Permission::UserUpdateRole => "user:update_role",
+            Permission::UserImpersonate => "user:impersonate",
+            Permission::MfaPolicyManage => "mfa_policy:manage",
+            Permission::PropertyCreate => "property:create",
+            // Edit, delete and restore any listing
+            Permission::PropertyManage => "property:manage",
+            Permission::AuditRead => "audit:read",
         }
     }
 }
 
-// Create a middleware state struct
+// Looks at the role currently stored for the user rather than the one in
+// the token, so a role change takes effect immediately
+pub async fn has_permission(
+    pool: &SqlitePool,
+    user_id: i64,
+    permission: Permission,
+) -> Result<bool, ApiError> {
+    let permission = permission.as_str();
+
+    let granted = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1
+            FROM users u
+            JOIN role_permissions rp ON rp.role = u.role
+            WHERE u.id = ? AND rp.permission = ?
+        ) as "granted: bool"
+        "#,
+        user_id,
+        permission
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    Ok(granted)
+}
+
+// Create a middleware state struct. It carries the app state so the
+// AuthUser extractor can reach the keyring.
 #[derive(Clone)]
-pub struct RequireRole(pub Role);
+pub struct RequirePermission {
+    pub app: AppState,
+    pub permission: Permission,
+}
 
-pub async fn require_role(
-    auth_user: AuthUser,
-    State(role): State<RequireRole>,
-    request: Request<Body>,
+impl FromRef<RequirePermission> for AppState {
+    fn from_ref(required: &RequirePermission) -> Self {
+        required.app.clone()
+    }
+}
+
+pub async fn require_permission(
+    AnyCredential(auth_user): AnyCredential,
+    State(required): State<RequirePermission>,
+    mut request: Request<Body>,
     next: Next,
 ) -> Result<Response, ApiError> {
-    let user_role = Role::from_str(&auth_user.role)
-        .ok_or_else(|| ApiError::AuthorizationError("Invalid role".to_string()))?;
-
-    match (user_role, &role.0) {
-        (Role::Admin, _) => Ok(next.run(request).await),
-        (Role::Agent, Role::User) | (Role::Agent, Role::Agent) => Ok(next.run(request).await),
-        (Role::User, Role::User) => Ok(next.run(request).await),
-        _ => Err(ApiError::AuthorizationError(
+    if !has_permission(&required.app.pool, auth_user.user_id, required.permission).await? {
+        return Err(ApiError::AuthorizationError(
             "Insufficient permissions".to_string(),
-        )),
+        ));
+    }
+    if !auth_user.in_scope(required.permission) {
+        return Err(ApiError::AuthorizationError(format!(
+            "API key is missing the {} scope",
+            required.permission.as_str()
+        )));
     }
-    // Response type in Axum has a default body type, so we don't need to specify it
+
+    request.extensions_mut().insert(ScopeChecked);
+    Ok(next.run(request).await)
 }
 
-// Add refresh token functionality
-#[derive(Debug, Serialize, Deserialize)]
-pub struct RefreshToken {
-    pub token: String,
+// A session is one login on one device. Its id doubles as the family id of
+// the refresh tokens issued for it.
+pub async fn create_session(
+    executor: impl SqliteExecutor<'_>,
+    user: &User,
+    client: &ClientInfo,
+) -> Result<String, ApiError> {
+    let session_id = Uuid::new_v4().to_string();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO sessions (id, user_id, user_agent, ip_address)
+        VALUES (?, ?, ?, ?)
+        "#,
+        session_id,
+        user.id,
+        client.user_agent,
+        client.ip_address
+    )
+    .execute(executor)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    Ok(session_id)
+}
+
+// Ends every session of a user, e.g. after a password reset
+pub async fn revoke_all_sessions(
+    executor: impl SqliteExecutor<'_>,
+    user_id: i64,
+) -> Result<(), ApiError> {
+    sqlx::query!(
+        r#"
+        UPDATE sessions
+        SET revoked_at = CURRENT_TIMESTAMP
+        WHERE user_id = ? AND revoked_at IS NULL
+        "#,
+        user_id
+    )
+    .execute(executor)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    Ok(())
 }
 
-pub fn create_refresh_token(user: &User) -> Result<String, ApiError> {
+// Ends every session but the one that made a security change, e.g. a new
+// password or email address
+pub async fn revoke_other_sessions(
+    executor: impl SqliteExecutor<'_>,
+    user_id: i64,
+    keep_session_id: &str,
+) -> Result<(), ApiError> {
+    sqlx::query!(
+        r#"
+        UPDATE sessions
+        SET revoked_at = CURRENT_TIMESTAMP
+        WHERE user_id = ? AND id != ? AND revoked_at IS NULL
+        "#,
+        user_id,
+        keep_session_id
+    )
+    .execute(executor)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    Ok(())
+}
+
+// Add refresh token functionality. Every refresh token is stored so it can
+// be used exactly once; a new token in the same family replaces it.
+pub async fn create_refresh_token(
+    executor: impl SqliteExecutor<'_>,
+    keys: &KeyRing,
+    user: &User,
+    session_id: &str,
+) -> Result<String, ApiError> {
     let now = OffsetDateTime::now_utc();
-    let expiry = now + Duration::days(30); // Refresh tokens last longer
+    let expiry = now + REFRESH_TOKEN_LIFETIME;
 
-    let claims = Claims {
+    let claims = RefreshClaims {
         sub: user.id.unwrap(),
         exp: expiry.unix_timestamp(),
         iat: now.unix_timestamp(),
-        role: user.role.to_string(),
+        typ: TokenType::Refresh,
+        jti: Uuid::new_v4().to_string(),
+        fam: session_id.to_string(),
     };
 
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(JWT_SECRET),
+    sqlx::query!(
+        r#"
+        INSERT INTO refresh_tokens (id, user_id, family_id, expires_at)
+        VALUES (?, ?, ?, datetime(?, 'unixepoch'))
+        "#,
+        claims.jti,
+        claims.sub,
+        claims.fam,
+        claims.exp
     )
-    .map_err(|_| ApiError::ValidationError("Refresh token creation failed".to_string()))
+    .execute(executor)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    keys.encode(&claims)
+        .map_err(|_| ApiError::ValidationError("Refresh token creation failed".to_string()))
+}
+
+// Opens a session and issues its first token pair once the user is fully
+// authenticated
+pub async fn start_session(
+    pool: &SqlitePool,
+    keys: &KeyRing,
+    user: User,
+    client: &ClientInfo,
+) -> Result<AuthResponse, ApiError> {
+    let mut tx = pool.begin().await.map_err(ApiError::DatabaseError)?;
+    let session_id = create_session(&mut *tx, &user, client).await?;
+    let token = create_token(keys, &user, &session_id)?;
+    let refresh_token = create_refresh_token(&mut *tx, keys, &user, &session_id).await?;
+    tx.commit().await.map_err(ApiError::DatabaseError)?;
+
+    Ok(AuthResponse {
+        token,
+        refresh_token,
+        user,
+    })
+}
+
+// Last step of every login method once the user is known: tokens, or an MFA
+// challenge when the account needs a second factor. `method` describes the
+// login in the audit log, which only records it once the user is signed in.
+pub async fn finish_login(
+    pool: &SqlitePool,
+    keys: &KeyRing,
+    user: User,
+    client: &ClientInfo,
+    method: Value,
+) -> Result<LoginResponse, ApiError> {
+    let user_id = user.id.unwrap();
+    if let Some(enrollment_required) = mfa::login_requirement(pool, &user).await? {
+        let challenge_token = mfa::create_challenge(pool, user_id).await?;
+        return Ok(LoginResponse::MfaRequired(MfaChallenge {
+            mfa_required: true,
+            enrollment_required,
+            challenge_token,
+        }));
+    }
+
+    let response = start_session(pool, keys, user, client).await?;
+    AuditEvent::new("auth.login")
+        .by_user(user_id)
+        .target("user", user_id)
+        .after(method)
+        .record(pool, client)
+        .await?;
+
+    Ok(LoginResponse::Authenticated(Box::new(response)))
 }
@@ -0,0 +1,137 @@
+// Application settings read from the environment
+
+use anyhow::{bail, Context};
+use axum_extra::extract::cookie::SameSite;
+
+use crate::models::UserRole;
+
+pub struct Config {
+    // Base URL of the web frontend, used to build links in emails
+    pub app_url: String,
+    // Seconds a user has to wait before asking for another verification email
+    pub verification_resend_cooldown: i64,
+    // Roles that need a verified email address for each gated action
+    pub verified_roles_for_listings: Vec<String>,
+    pub verified_roles_for_messaging: Vec<String>,
+    // Password reset requests allowed per hour
+    pub password_reset_limit_per_email: i64,
+    pub password_reset_limit_per_ip: i64,
+    // Issuer shown in authenticator apps
+    pub mfa_issuer: String,
+    // Failed logins before an account or client IP is locked out
+    pub login_max_failures: i64,
+    pub login_max_failures_per_ip: i64,
+    pub login_lockout_seconds: i64,
+    // Password policy
+    pub password_min_length: usize,
+    pub password_max_length: usize,
+    pub password_min_character_classes: usize,
+    // Directory of Have I Been Pwned range files checked on top of the
+    // bundled list
+    pub password_breached_ranges_dir: Option<String>,
+    // Lifetime of API keys created without an explicit expiry, and the
+    // longest one allowed
+    pub api_key_default_days: i64,
+    pub api_key_max_days: i64,
+    // Lifetime of the token an admin gets to act as another user
+    pub impersonation_token_minutes: i64,
+    // Lets browser clients keep their tokens in HttpOnly cookies
+    pub cookie_sessions: bool,
+    // Only turn off for local development over plain http
+    pub cookie_secure: bool,
+    pub cookie_same_site: SameSite,
+    pub cookie_domain: Option<String>,
+}
+
+impl Config {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let config = Config {
+            app_url: std::env::var("APP_URL")
+                .unwrap_or_else(|_| "http://localhost:3000".to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            verification_resend_cooldown: env_parse("VERIFICATION_RESEND_COOLDOWN", 60)?,
+            verified_roles_for_listings: env_roles(
+                "REQUIRE_VERIFIED_FOR_LISTINGS",
+                "seller,owner,agent",
+            )?,
+            verified_roles_for_messaging: env_roles(
+                "REQUIRE_VERIFIED_FOR_MESSAGING",
+                "seller,buyer,owner,tenant,agent",
+            )?,
+            password_reset_limit_per_email: env_parse("PASSWORD_RESET_LIMIT_PER_EMAIL", 3)?,
+            password_reset_limit_per_ip: env_parse("PASSWORD_RESET_LIMIT_PER_IP", 10)?,
+            mfa_issuer: std::env::var("MFA_ISSUER").unwrap_or_else(|_| "yRealEstate".to_string()),
+            login_max_failures: env_parse("LOGIN_MAX_FAILURES", 10)?,
+            login_max_failures_per_ip: env_parse("LOGIN_MAX_FAILURES_PER_IP", 50)?,
+            login_lockout_seconds: env_parse("LOGIN_LOCKOUT_SECONDS", 15 * 60)?,
+            password_min_length: env_parse("PASSWORD_MIN_LENGTH", 10)?,
+            password_max_length: env_parse("PASSWORD_MAX_LENGTH", 128)?,
+            password_min_character_classes: env_parse("PASSWORD_MIN_CHARACTER_CLASSES", 3)?,
+            password_breached_ranges_dir: std::env::var("PASSWORD_BREACHED_RANGES_DIR").ok(),
+            api_key_default_days: env_parse("API_KEY_DEFAULT_DAYS", 90)?,
+            api_key_max_days: env_parse("API_KEY_MAX_DAYS", 365)?,
+            impersonation_token_minutes: env_parse("IMPERSONATION_TOKEN_MINUTES", 15)?,
+            cookie_sessions: env_parse("COOKIE_SESSIONS", false)?,
+            cookie_secure: env_parse("COOKIE_SECURE", true)?,
+            cookie_same_site: same_site_from_env()?,
+            cookie_domain: std::env::var("COOKIE_DOMAIN").ok(),
+        };
+
+        // Browsers drop SameSite=None cookies that aren't Secure
+        if config.cookie_same_site == SameSite::None && !config.cookie_secure {
+            bail!("COOKIE_SAME_SITE=none requires COOKIE_SECURE=true");
+        }
+
+        Ok(config)
+    }
+}
+
+fn env_parse<T>(name: &str, default: T) -> anyhow::Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .with_context(|| format!("{name} has an invalid value")),
+        Err(_) => Ok(default),
+    }
+}
+
+fn same_site_from_env() -> anyhow::Result<SameSite> {
+    let value = std::env::var("COOKIE_SAME_SITE").unwrap_or_else(|_| "strict".to_string());
+    match value.to_lowercase().as_str() {
+        "strict" => Ok(SameSite::Strict),
+        "lax" => Ok(SameSite::Lax),
+        "none" => Ok(SameSite::None),
+        _ => bail!("COOKIE_SAME_SITE must be strict, lax or none"),
+    }
+}
+
+// Comma-separated, lowercased role names. An empty value disables the
+// setting; a misspelt role fails startup instead of being skipped.
+fn env_roles(name: &str, default: &str) -> anyhow::Result<Vec<String>> {
+    let roles: Vec<String> = std::env::var(name)
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .map(|item| item.trim().to_lowercase())
+        .filter(|item| !item.is_empty())
+        .collect();
+
+    let known = [
+        UserRole::Admin,
+        UserRole::Seller,
+        UserRole::Buyer,
+        UserRole::Owner,
+        UserRole::Tenant,
+        UserRole::Agent,
+    ]
+    .map(|role| role.to_string());
+    if let Some(unknown) = roles.iter().find(|role| !known.contains(role)) {
+        bail!("Unknown role `{unknown}` in {name}");
+    }
+
+    Ok(roles)
+}
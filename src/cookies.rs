@@ -0,0 +1,193 @@
+// Cookie transport for the web frontend. Browser clients opt in per request
+// with `X-Auth-Mode: cookie` so tokens never reach JavaScript, everyone
+// else keeps using bearer tokens.
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::{request::Parts, HeaderMap, Method},
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar};
+use time::Duration;
+
+use crate::auth::{AuthResponse, LoginResponse, ACCESS_TOKEN_LIFETIME, REFRESH_TOKEN_LIFETIME};
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::state::AppState;
+use crate::tokens::new_token;
+
+pub const ACCESS_COOKIE: &str = "yre_access";
+pub const REFRESH_COOKIE: &str = "yre_refresh";
+// Readable by the frontend, which echoes it in the CSRF header
+pub const CSRF_COOKIE: &str = "yre_csrf";
+const CSRF_HEADER: &str = "x-csrf-token";
+const MODE_HEADER: &str = "x-auth-mode";
+
+// The refresh cookie is only sent to the one route that needs it
+const ACCESS_PATH: &str = "/api";
+const REFRESH_PATH: &str = "/api/refresh";
+const CSRF_PATH: &str = "/";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthMode {
+    Bearer,
+    Cookie,
+}
+
+impl AuthMode {
+    // Moves the tokens from the body into cookies, along with a fresh CSRF
+    // token. In bearer mode the jar stays empty.
+    pub fn deliver(self, config: &Config, auth: &mut AuthResponse) -> CookieJar {
+        let jar = CookieJar::new();
+        if self == AuthMode::Bearer {
+            return jar;
+        }
+
+        let access_token = std::mem::take(&mut auth.token);
+        let refresh_token = std::mem::take(&mut auth.refresh_token);
+        let mut csrf = build(
+            config,
+            CSRF_COOKIE,
+            new_token(),
+            CSRF_PATH,
+            REFRESH_TOKEN_LIFETIME,
+        );
+        csrf.set_http_only(false);
+
+        jar.add(build(
+            config,
+            ACCESS_COOKIE,
+            access_token,
+            ACCESS_PATH,
+            ACCESS_TOKEN_LIFETIME,
+        ))
+        .add(build(
+            config,
+            REFRESH_COOKIE,
+            refresh_token,
+            REFRESH_PATH,
+            REFRESH_TOKEN_LIFETIME,
+        ))
+        .add(csrf)
+    }
+
+    // An MFA challenge has no tokens yet, the next login step delivers them
+    pub fn deliver_login(self, config: &Config, response: &mut LoginResponse) -> CookieJar {
+        match response {
+            LoginResponse::Authenticated(auth) => self.deliver(config, auth),
+            LoginResponse::MfaRequired(_) => CookieJar::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthMode
+where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Some(mode) = parts.headers.get(MODE_HEADER) else {
+            return Ok(AuthMode::Bearer);
+        };
+
+        match mode.to_str().map(str::to_lowercase).as_deref() {
+            Ok("bearer") => Ok(AuthMode::Bearer),
+            Ok("cookie") if AppState::from_ref(state).config.cookie_sessions => {
+                Ok(AuthMode::Cookie)
+            }
+            Ok("cookie") => Err(ApiError::ValidationError(
+                "Cookie sessions are not enabled".to_string(),
+            )),
+            _ => Err(ApiError::ValidationError(
+                "X-Auth-Mode must be bearer or cookie".to_string(),
+            )),
+        }
+    }
+}
+
+fn build(
+    config: &Config,
+    name: &'static str,
+    value: String,
+    path: &'static str,
+    max_age: Duration,
+) -> Cookie<'static> {
+    let mut cookie = Cookie::build((name, value))
+        .path(path)
+        .max_age(max_age)
+        .http_only(true)
+        .secure(config.cookie_secure)
+        .same_site(config.cookie_same_site)
+        .build();
+    if let Some(domain) = &config.cookie_domain {
+        cookie.set_domain(domain.clone());
+    }
+    cookie
+}
+
+// Expires all session cookies. Harmless for bearer clients, which ignore
+// Set-Cookie.
+pub fn clear(config: &Config) -> CookieJar {
+    let mut jar = CookieJar::new();
+    if !config.cookie_sessions {
+        return jar;
+    }
+
+    for (name, path) in [
+        (ACCESS_COOKIE, ACCESS_PATH),
+        (REFRESH_COOKIE, REFRESH_PATH),
+        (CSRF_COOKIE, CSRF_PATH),
+    ] {
+        let mut cookie = build(config, name, String::new(), path, Duration::ZERO);
+        cookie.make_removal();
+        jar = jar.add(cookie);
+    }
+    jar
+}
+
+// Access token sent as a cookie, for requests without an Authorization
+// header. State-changing requests must also pass the CSRF check.
+pub fn access_token(config: &Config, parts: &Parts) -> Result<Option<String>, ApiError> {
+    if !config.cookie_sessions {
+        return Ok(None);
+    }
+
+    let jar = CookieJar::from_headers(&parts.headers);
+    let Some(cookie) = jar.get(ACCESS_COOKIE) else {
+        return Ok(None);
+    };
+    check_csrf(&parts.method, &parts.headers, &jar)?;
+
+    Ok(Some(cookie.value().to_string()))
+}
+
+pub fn refresh_token(headers: &HeaderMap) -> Result<String, ApiError> {
+    let jar = CookieJar::from_headers(headers);
+    check_csrf(&Method::POST, headers, &jar)?;
+
+    jar.get(REFRESH_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .ok_or_else(|| ApiError::AuthenticationError("Invalid refresh token".to_string()))
+}
+
+// Double-submit check: a cross-site page can make the browser send our
+// cookies, but it can't read the CSRF cookie to copy it into the header
+fn check_csrf(method: &Method, headers: &HeaderMap, jar: &CookieJar) -> Result<(), ApiError> {
+    if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        return Ok(());
+    }
+
+    let cookie = jar.get(CSRF_COOKIE).map(|cookie| cookie.value());
+    let header = headers
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok());
+    match (cookie, header) {
+        (Some(cookie), Some(header)) if !cookie.is_empty() && cookie == header => Ok(()),
+        _ => Err(ApiError::AuthorizationError(
+            "Missing or invalid CSRF token".to_string(),
+        )),
+    }
+}
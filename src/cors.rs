@@ -0,0 +1,196 @@
+// Cross-origin policy for browser clients. A profile supplies defaults per
+// environment and each CORS_* variable overrides one setting.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{bail, Context};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{
+        header::{self, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN},
+        HeaderName, HeaderValue, Method, Request, Uri,
+    },
+    middleware::Next,
+    response::Response,
+};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::config::Config;
+use crate::error::ApiError;
+
+const DEFAULT_METHODS: &str = "GET,POST,PUT,PATCH,DELETE";
+const DEFAULT_HEADERS: &str = "authorization,content-type,if-match,x-auth-mode,x-csrf-token";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CorsProfile {
+    // Only the frontend at APP_URL
+    Production,
+    // Also any http://localhost or http://127.0.0.1 port, for dev servers
+    Development,
+}
+
+#[derive(Debug)]
+pub struct CorsPolicy {
+    pub profile: CorsProfile,
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<Method>,
+    pub allowed_headers: Vec<HeaderName>,
+    pub max_age: Duration,
+    pub allow_credentials: bool,
+}
+
+impl CorsPolicy {
+    pub fn from_env(config: &Config) -> anyhow::Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+
+        let profile = match var("CORS_PROFILE").as_deref() {
+            None | Some("production") => CorsProfile::Production,
+            Some("development") => CorsProfile::Development,
+            Some(other) => bail!("unknown CORS_PROFILE {other}"),
+        };
+
+        let allowed_origins = match var("CORS_ALLOWED_ORIGINS") {
+            Some(origins) => {
+                // Browsers refuse credentials with a wildcard origin anyway
+                if split(&origins).any(|origin| origin == "*") {
+                    bail!("CORS_ALLOWED_ORIGINS must list origins explicitly");
+                }
+                split(&origins)
+                    .map(|origin| {
+                        origin_of(origin).with_context(|| {
+                            format!("invalid origin {origin} in CORS_ALLOWED_ORIGINS")
+                        })
+                    })
+                    .collect::<anyhow::Result<_>>()?
+            }
+            None => vec![origin_of(&config.app_url).context("APP_URL is not a valid URL")?],
+        };
+
+        let allowed_methods = split(&var("CORS_ALLOWED_METHODS").unwrap_or(DEFAULT_METHODS.into()))
+            .map(|method| {
+                Method::from_bytes(method.to_uppercase().as_bytes())
+                    .with_context(|| format!("invalid method {method} in CORS_ALLOWED_METHODS"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        let allowed_headers = split(&var("CORS_ALLOWED_HEADERS").unwrap_or(DEFAULT_HEADERS.into()))
+            .map(|header| {
+                HeaderName::from_bytes(header.to_lowercase().as_bytes())
+                    .with_context(|| format!("invalid header {header} in CORS_ALLOWED_HEADERS"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        let default_max_age = match profile {
+            CorsProfile::Production => 3600,
+            CorsProfile::Development => 60,
+        };
+        let max_age = match var("CORS_MAX_AGE") {
+            Some(seconds) => seconds
+                .parse()
+                .context("CORS_MAX_AGE has an invalid value")?,
+            None => default_max_age,
+        };
+
+        // Cookie sessions need credentialed requests
+        let allow_credentials = match var("CORS_ALLOW_CREDENTIALS") {
+            Some(value) => value
+                .parse()
+                .context("CORS_ALLOW_CREDENTIALS has an invalid value")?,
+            None => config.cookie_sessions,
+        };
+
+        Ok(CorsPolicy {
+            profile,
+            allowed_origins,
+            allowed_methods,
+            allowed_headers,
+            max_age: Duration::from_secs(max_age),
+            allow_credentials,
+        })
+    }
+
+    pub fn is_allowed(&self, origin: &str) -> bool {
+        let origin = origin.to_lowercase();
+        if self.allowed_origins.contains(&origin) {
+            return true;
+        }
+
+        self.profile == CorsProfile::Development
+            && ["http://localhost", "http://127.0.0.1"].iter().any(|host| {
+                origin.strip_prefix(host).is_some_and(|port| {
+                    port.is_empty()
+                        || port
+                            .strip_prefix(':')
+                            .is_some_and(|port| port.parse::<u16>().is_ok())
+                })
+            })
+    }
+
+    pub fn layer(self: &Arc<Self>) -> CorsLayer {
+        let policy = self.clone();
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::predicate(move |origin: &HeaderValue, _| {
+                origin
+                    .to_str()
+                    .is_ok_and(|origin| policy.is_allowed(origin))
+            }))
+            .allow_methods(self.allowed_methods.clone())
+            .allow_headers(self.allowed_headers.clone())
+            // Needed for If-Match on listing updates
+            .expose_headers([header::ETAG])
+            .max_age(self.max_age)
+            .allow_credentials(self.allow_credentials)
+    }
+}
+
+// The `scheme://host[:port]` a browser sends as Origin for pages under the URL
+fn origin_of(url: &str) -> anyhow::Result<String> {
+    let uri: Uri = url.parse()?;
+    let (Some(scheme), Some(authority)) = (uri.scheme_str(), uri.authority()) else {
+        bail!("missing scheme or host");
+    };
+    Ok(format!("{scheme}://{authority}").to_lowercase())
+}
+
+fn split(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+// Runs in front of the CORS layer. Preflights from unknown origins get a
+// 403 instead of a response without CORS headers; other requests from them
+// go through, since the browser withholds the response anyway and
+// non-browser clients may send any Origin.
+pub async fn reject_unknown_origins(
+    State(policy): State<Arc<CorsPolicy>>,
+    request: Request<Body>,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let origin = request
+        .headers()
+        .get(ORIGIN)
+        .map(|origin| origin.to_str().unwrap_or_default());
+
+    if let Some(origin) = origin.filter(|origin| !policy.is_allowed(origin)) {
+        let preflight = request.method() == Method::OPTIONS
+            && request
+                .headers()
+                .contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+        tracing::warn!(
+            origin,
+            method = %request.method(),
+            path = %request.uri().path(),
+            preflight,
+            "request from disallowed origin"
+        );
+        if preflight {
+            return Err(ApiError::AuthorizationError(
+                "Origin not allowed".to_string(),
+            ));
+        }
+    }
+
+    Ok(next.run(request).await)
+}
@@ -12,19 +12,38 @@ pub enum ApiError {
     ValidationError(String),
     AuthenticationError(String),
     AuthorizationError(String),
+    TooManyRequests(String),
+    // If-Match didn't match the current version
+    PreconditionFailed(String),
+    // If-Match was missing on a write that needs it
+    PreconditionRequired(String),
+    InternalError(String),
 }
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
         let (status, error_message) = match self {
-            ApiError::DatabaseError(_) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Database error".to_string(),
-            ),
+            ApiError::DatabaseError(err) => {
+                tracing::error!("database error: {}", err);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Database error".to_string(),
+                )
+            }
             ApiError::NotFound => (StatusCode::NOT_FOUND, "Not found".to_string()),
             ApiError::ValidationError(msg) => (StatusCode::BAD_REQUEST, msg),
             ApiError::AuthenticationError(msg) => (StatusCode::UNAUTHORIZED, msg),
             ApiError::AuthorizationError(msg) => (StatusCode::FORBIDDEN, msg),
+            ApiError::TooManyRequests(msg) => (StatusCode::TOO_MANY_REQUESTS, msg),
+            ApiError::PreconditionFailed(msg) => (StatusCode::PRECONDITION_FAILED, msg),
+            ApiError::PreconditionRequired(msg) => (StatusCode::PRECONDITION_REQUIRED, msg),
+            ApiError::InternalError(msg) => {
+                tracing::error!("internal error: {}", msg);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Internal server error".to_string(),
+                )
+            }
         };
 
         let body = Json(json!({
@@ -0,0 +1,78 @@
+// Optimistic concurrency for listings. The ETag is the row's version, which
+// every write bumps, so a write based on a stale read is refused.
+
+use axum::http::{header::IF_MATCH, HeaderMap, HeaderValue};
+
+use crate::error::ApiError;
+
+pub fn etag(version: i64) -> HeaderValue {
+    HeaderValue::from_str(&format!("\"{version}\"")).expect("a quoted number is a valid header")
+}
+
+// Writes must send If-Match with the ETag they read; `*` opts out
+pub fn require_match(headers: &HeaderMap, version: i64) -> Result<(), ApiError> {
+    let if_match = headers.get(IF_MATCH).ok_or_else(|| {
+        ApiError::PreconditionRequired("If-Match with the listing's ETag is required".to_string())
+    })?;
+    let if_match = if_match
+        .to_str()
+        .map_err(|_| ApiError::ValidationError("Invalid If-Match header".to_string()))?;
+
+    // Strong comparison, a weak W/ tag never matches
+    let current = format!("\"{version}\"");
+    if !if_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag == current)
+    {
+        return Err(ApiError::PreconditionFailed(
+            "The listing was changed since it was read".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn if_match(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_MATCH, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn etag_is_the_quoted_version() {
+        assert_eq!(etag(7), "\"7\"");
+    }
+
+    #[test]
+    fn missing_if_match_is_required() {
+        assert!(matches!(
+            require_match(&HeaderMap::new(), 1),
+            Err(ApiError::PreconditionRequired(_))
+        ));
+    }
+
+    #[test]
+    fn current_tag_or_star_matches() {
+        assert!(require_match(&if_match("\"3\""), 3).is_ok());
+        assert!(require_match(&if_match("*"), 3).is_ok());
+        assert!(require_match(&if_match("\"1\", \"3\""), 3).is_ok());
+    }
+
+    #[test]
+    fn stale_or_weak_tag_fails() {
+        for value in ["\"2\"", "W/\"3\"", "3", "\"1\", \"2\""] {
+            assert!(
+                matches!(
+                    require_match(&if_match(value), 3),
+                    Err(ApiError::PreconditionFailed(_))
+                ),
+                "{value} should not match"
+            );
+        }
+    }
+}
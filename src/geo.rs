@@ -0,0 +1,242 @@
+// Great-circle distances and bounding boxes for property coordinates
+
+use std::str::FromStr;
+
+use crate::error::ApiError;
+
+// Mean earth radius
+pub const EARTH_RADIUS_KM: f64 = 6371.0088;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Point {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Point {
+    // Both coordinates or neither; a listing may have no position
+    pub fn from_parts(
+        latitude: Option<f64>,
+        longitude: Option<f64>,
+    ) -> Result<Option<Point>, ApiError> {
+        match (latitude, longitude) {
+            (None, None) => Ok(None),
+            (Some(latitude), Some(longitude)) => {
+                if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+                    return Err(ApiError::ValidationError(
+                        "Latitude must be within ±90 and longitude within ±180".to_string(),
+                    ));
+                }
+                Ok(Some(Point {
+                    latitude,
+                    longitude,
+                }))
+            }
+            _ => Err(ApiError::ValidationError(
+                "Latitude and longitude must be given together".to_string(),
+            )),
+        }
+    }
+
+    // The point on the unit sphere, stored as properties.geo_x/y/z
+    pub fn unit_vector(self) -> [f64; 3] {
+        let (latitude, longitude) = (self.latitude.to_radians(), self.longitude.to_radians());
+        [
+            latitude.cos() * longitude.cos(),
+            latitude.cos() * longitude.sin(),
+            latitude.sin(),
+        ]
+    }
+
+    // Box that contains every point within the radius. Near a pole, or for
+    // a radius that large, it spans all longitudes.
+    pub fn bounding_box(self, radius_km: f64) -> BoundingBox {
+        let angle = radius_km / EARTH_RADIUS_KM;
+        let min_latitude = self.latitude - angle.to_degrees();
+        let max_latitude = self.latitude + angle.to_degrees();
+        let longitude_ratio = angle.sin() / self.latitude.to_radians().cos();
+        if min_latitude <= -90.0
+            || max_latitude >= 90.0
+            || angle >= std::f64::consts::FRAC_PI_2
+            || longitude_ratio >= 1.0
+        {
+            return BoundingBox {
+                min_longitude: -180.0,
+                min_latitude: min_latitude.max(-90.0),
+                max_longitude: 180.0,
+                max_latitude: max_latitude.min(90.0),
+            };
+        }
+
+        let longitude_angle = longitude_ratio.asin().to_degrees();
+        BoundingBox {
+            min_longitude: wrap_longitude(self.longitude - longitude_angle),
+            min_latitude,
+            max_longitude: wrap_longitude(self.longitude + longitude_angle),
+            max_latitude,
+        }
+    }
+}
+
+fn wrap_longitude(longitude: f64) -> f64 {
+    if longitude < -180.0 {
+        longitude + 360.0
+    } else if longitude > 180.0 {
+        longitude - 360.0
+    } else {
+        longitude
+    }
+}
+
+// Haversine of the central angle for a distance, (1 - cos θ) / 2. It
+// grows with the distance, so searches compare and sort by it directly.
+pub fn haversine_of_distance(distance_km: f64) -> f64 {
+    (distance_km / EARTH_RADIUS_KM / 2.0).sin().powi(2)
+}
+
+pub fn distance_from_haversine(haversine: f64) -> f64 {
+    2.0 * EARTH_RADIUS_KM * haversine.clamp(0.0, 1.0).sqrt().asin()
+}
+
+// `min_lng,min_lat,max_lng,max_lat` as in GeoJSON. A box whose west edge is
+// east of its east edge crosses the antimeridian.
+#[derive(Debug, Clone, Copy)]
+pub struct BoundingBox {
+    pub min_longitude: f64,
+    pub min_latitude: f64,
+    pub max_longitude: f64,
+    pub max_latitude: f64,
+}
+
+impl BoundingBox {
+    pub fn crosses_antimeridian(&self) -> bool {
+        self.min_longitude > self.max_longitude
+    }
+}
+
+impl FromStr for BoundingBox {
+    type Err = ApiError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            ApiError::ValidationError(
+                "bbox must be min_lng,min_lat,max_lng,max_lat in degrees".to_string(),
+            )
+        };
+
+        let parts = value
+            .split(',')
+            .map(|part| part.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        let [min_longitude, min_latitude, max_longitude, max_latitude] = parts[..] else {
+            return Err(invalid());
+        };
+
+        let latitudes = -90.0..=90.0;
+        let longitudes = -180.0..=180.0;
+        if !latitudes.contains(&min_latitude)
+            || !latitudes.contains(&max_latitude)
+            || !longitudes.contains(&min_longitude)
+            || !longitudes.contains(&max_longitude)
+            || min_latitude > max_latitude
+        {
+            return Err(invalid());
+        }
+
+        Ok(BoundingBox {
+            min_longitude,
+            min_latitude,
+            max_longitude,
+            max_latitude,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(latitude: f64, longitude: f64) -> Point {
+        Point {
+            latitude,
+            longitude,
+        }
+    }
+
+    #[test]
+    fn bounding_box_contains_the_radius() {
+        let bbox = point(52.52, 13.405).bounding_box(10.0);
+        let degrees = (10.0 / EARTH_RADIUS_KM).to_degrees();
+        assert!((bbox.max_latitude - 52.52 - degrees).abs() < 1e-9);
+        assert!((52.52 - bbox.min_latitude - degrees).abs() < 1e-9);
+        // Longitude degrees are shorter away from the equator
+        assert!(bbox.max_longitude - 13.405 > degrees);
+        assert!(!bbox.crosses_antimeridian());
+    }
+
+    #[test]
+    fn bounding_box_near_a_pole_spans_all_longitudes() {
+        let bbox = point(89.95, 10.0).bounding_box(20.0);
+        assert_eq!(bbox.min_longitude, -180.0);
+        assert_eq!(bbox.max_longitude, 180.0);
+        assert_eq!(bbox.max_latitude, 90.0);
+
+        let bbox = point(-89.95, 10.0).bounding_box(20.0);
+        assert_eq!(bbox.min_latitude, -90.0);
+        assert_eq!(bbox.min_longitude, -180.0);
+    }
+
+    #[test]
+    fn bounding_box_for_a_huge_radius_spans_all_longitudes() {
+        let bbox = point(0.0, 0.0).bounding_box(15_000.0);
+        assert_eq!(bbox.min_longitude, -180.0);
+        assert_eq!(bbox.max_longitude, 180.0);
+    }
+
+    #[test]
+    fn bounding_box_wraps_across_the_antimeridian() {
+        let bbox = point(-17.7, 179.9).bounding_box(50.0);
+        assert!(bbox.crosses_antimeridian());
+        assert!(bbox.min_longitude > 179.0);
+        assert!(bbox.max_longitude < -179.0);
+
+        let bbox = point(-17.7, -179.9).bounding_box(50.0);
+        assert!(bbox.crosses_antimeridian());
+    }
+
+    #[test]
+    fn bbox_parses_from_a_query() {
+        let bbox: BoundingBox = " 13.0, 52.3,13.8,52.7".parse().unwrap();
+        assert_eq!(bbox.min_longitude, 13.0);
+        assert_eq!(bbox.min_latitude, 52.3);
+        assert_eq!(bbox.max_longitude, 13.8);
+        assert_eq!(bbox.max_latitude, 52.7);
+        assert!(!bbox.crosses_antimeridian());
+
+        let bbox: BoundingBox = "179,-20,-179,-15".parse().unwrap();
+        assert!(bbox.crosses_antimeridian());
+    }
+
+    #[test]
+    fn bbox_rejects_invalid_input() {
+        for value in [
+            "",
+            "1,2,3",
+            "1,2,3,4,5",
+            "a,2,3,4",
+            "-181,0,0,1",
+            "0,-91,1,1",
+            "0,0,1,91",
+            "0,10,1,5",
+        ] {
+            assert!(
+                matches!(
+                    value.parse::<BoundingBox>(),
+                    Err(ApiError::ValidationError(_))
+                ),
+                "{value:?} should be rejected"
+            );
+        }
+    }
+}
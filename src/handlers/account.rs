@@ -0,0 +1,293 @@
+// Self-service changes to the signed-in user's credentials
+
+use crate::audit::AuditEvent;
+use crate::auth::{revoke_other_sessions, AuthUser};
+use crate::client_info::ClientInfo;
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::login_attempts::{self, AttemptStore};
+use crate::mail::{MailQueue, Template};
+use crate::models::{EmailChangeConfirm, EmailChangeRequest, PasswordChange};
+use crate::password;
+use crate::password_policy;
+use crate::tokens::{hash_token, new_token};
+use axum::extract::{Json, State};
+use serde_json::json;
+use sqlx::SqlitePool;
+use std::sync::Arc;
+
+// Returns the user's email once the password checks out. Wrong passwords
+// count towards the same lockout as failed logins, so a stolen session
+// can't be used to guess it.
+async fn check_current_password(
+    pool: &SqlitePool,
+    config: &Config,
+    attempts: &dyn AttemptStore,
+    mail: &MailQueue,
+    client: &ClientInfo,
+    user_id: i64,
+    current_password: &str,
+) -> Result<String, ApiError> {
+    let user = sqlx::query!(
+        "SELECT email, password_hash FROM users WHERE id = ?",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .ok_or(ApiError::NotFound)?;
+
+    let ip = client.ip_address.as_deref();
+    login_attempts::check(attempts, &user.email, ip).await?;
+
+    if !password::verify(current_password, &user.password_hash)
+        .await?
+        .valid
+    {
+        AuditEvent::new("auth.password_failed")
+            .by_user(user_id)
+            .target("user", user_id)
+            .record(pool, client)
+            .await?;
+        let locked = login_attempts::record_failure(attempts, config, &user.email, ip).await?;
+        if locked {
+            login_attempts::notify_locked(mail, config, &user.email).await?;
+        }
+        return Err(ApiError::ValidationError(
+            "Current password is incorrect".to_string(),
+        ));
+    }
+
+    Ok(user.email)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn change_password(
+    auth_user: AuthUser,
+    State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+    State(attempts): State<Arc<dyn AttemptStore>>,
+    State(mail): State<MailQueue>,
+    client: ClientInfo,
+    Json(change): Json<PasswordChange>,
+) -> Result<Json<()>, ApiError> {
+    let session_id = auth_user.require_session()?;
+    let email = check_current_password(
+        &pool,
+        &config,
+        attempts.as_ref(),
+        &mail,
+        &client,
+        auth_user.user_id,
+        &change.current_password,
+    )
+    .await?;
+    if change.new_password == change.current_password {
+        return Err(ApiError::ValidationError(
+            "New password must differ from the current one".to_string(),
+        ));
+    }
+    password_policy::check(&config, &change.new_password).await?;
+
+    let password_hash = password::hash(&change.new_password).await?;
+
+    let mut tx = pool.begin().await.map_err(ApiError::DatabaseError)?;
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET password_hash = ?, updated_at = CURRENT_TIMESTAMP
+        WHERE id = ?
+        "#,
+        password_hash,
+        auth_user.user_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+    revoke_other_sessions(&mut *tx, auth_user.user_id, session_id).await?;
+    AuditEvent::new("user.change_password")
+        .by(&auth_user)
+        .target("user", auth_user.user_id)
+        .record(&mut *tx, &client)
+        .await?;
+    tx.commit().await.map_err(ApiError::DatabaseError)?;
+
+    mail.enqueue(
+        &email,
+        Template::Notification {
+            subject: "Your password was changed".to_string(),
+            message: "The password of your yRealEstate account was just changed and \
+                      all other devices were signed out. If this was not you, please \
+                      reset your password and contact support immediately."
+                .to_string(),
+        },
+    )
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    Ok(Json(()))
+}
+
+// The address only changes once the link sent to it has been opened
+#[allow(clippy::too_many_arguments)]
+pub async fn request_email_change(
+    auth_user: AuthUser,
+    State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+    State(attempts): State<Arc<dyn AttemptStore>>,
+    State(mail): State<MailQueue>,
+    client: ClientInfo,
+    Json(request): Json<EmailChangeRequest>,
+) -> Result<Json<()>, ApiError> {
+    let session_id = auth_user.require_session()?;
+    let email = check_current_password(
+        &pool,
+        &config,
+        attempts.as_ref(),
+        &mail,
+        &client,
+        auth_user.user_id,
+        &request.current_password,
+    )
+    .await?;
+
+    let new_email = request.new_email.trim().to_lowercase();
+    if !new_email.contains('@') {
+        return Err(ApiError::ValidationError(
+            "Invalid email address".to_string(),
+        ));
+    }
+    if new_email == email.to_lowercase() {
+        return Err(ApiError::ValidationError(
+            "New email must differ from the current one".to_string(),
+        ));
+    }
+
+    let taken = sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM users WHERE lower(email) = ?) as "taken: bool""#,
+        new_email
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+    if taken {
+        return Err(ApiError::ValidationError(
+            "Email address is already in use".to_string(),
+        ));
+    }
+
+    let token = new_token();
+    let token_hash = hash_token(&token);
+
+    // Only the latest request can be confirmed
+    let mut tx = pool.begin().await.map_err(ApiError::DatabaseError)?;
+    sqlx::query!(
+        "DELETE FROM email_changes WHERE user_id = ? AND used_at IS NULL",
+        auth_user.user_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+    sqlx::query!(
+        r#"
+        INSERT INTO email_changes (user_id, session_id, new_email, token_hash, expires_at)
+        VALUES (?, ?, ?, ?, datetime('now', '+24 hours'))
+        "#,
+        auth_user.user_id,
+        session_id,
+        new_email,
+        token_hash
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+    AuditEvent::new("user.request_email_change")
+        .by(&auth_user)
+        .target("user", auth_user.user_id)
+        .before(json!({ "email": email }))
+        .after(json!({ "email": new_email }))
+        .record(&mut *tx, &client)
+        .await?;
+    tx.commit().await.map_err(ApiError::DatabaseError)?;
+
+    let link = format!("{}/confirm-email?token={}", config.app_url, token);
+    mail.enqueue(&new_email, Template::EmailChange { link })
+        .await
+        .map_err(ApiError::DatabaseError)?;
+
+    Ok(Json(()))
+}
+
+pub async fn confirm_email_change(
+    State(pool): State<SqlitePool>,
+    State(mail): State<MailQueue>,
+    client: ClientInfo,
+    Json(confirm): Json<EmailChangeConfirm>,
+) -> Result<Json<()>, ApiError> {
+    let token_hash = hash_token(&confirm.token);
+
+    let mut tx = pool.begin().await.map_err(ApiError::DatabaseError)?;
+    let change = sqlx::query!(
+        r#"
+        UPDATE email_changes
+        SET used_at = CURRENT_TIMESTAMP
+        WHERE token_hash = ? AND used_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+        RETURNING user_id, session_id, new_email
+        "#,
+        token_hash
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .ok_or_else(|| ApiError::ValidationError("Invalid or expired token".to_string()))?;
+
+    let old_email = sqlx::query_scalar!("SELECT email FROM users WHERE id = ?", change.user_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(ApiError::DatabaseError)?;
+
+    // Opening the link proves the new address works
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET email = ?, verified = true, updated_at = CURRENT_TIMESTAMP
+        WHERE id = ?
+        "#,
+        change.new_email,
+        change.user_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|err| match err {
+        // Someone else registered the address in the meantime
+        sqlx::Error::Database(db) if db.is_unique_violation() => {
+            ApiError::ValidationError("Email address is already in use".to_string())
+        }
+        err => ApiError::DatabaseError(err),
+    })?;
+    revoke_other_sessions(&mut *tx, change.user_id, &change.session_id).await?;
+    AuditEvent::new("user.change_email")
+        .by_user(change.user_id)
+        .target("user", change.user_id)
+        .before(json!({ "email": old_email }))
+        .after(json!({ "email": change.new_email }))
+        .record(&mut *tx, &client)
+        .await?;
+    tx.commit().await.map_err(ApiError::DatabaseError)?;
+
+    mail.enqueue(
+        &old_email,
+        Template::Notification {
+            subject: "Your email address was changed".to_string(),
+            message: format!(
+                "The email address of your yRealEstate account was changed to {}. \
+                 If this was not you, please contact support immediately.",
+                change.new_email
+            ),
+        },
+    )
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    Ok(Json(()))
+}
@@ -0,0 +1,157 @@
+use crate::api_keys;
+use crate::auth::{has_permission, AuthUser, Permission};
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::models::{ApiKey, CreatedApiKey, NewApiKey};
+use crate::tokens::hash_token;
+use axum::extract::{Json, Path, State};
+use sqlx::SqlitePool;
+use std::sync::Arc;
+
+struct ApiKeyRow {
+    id: i64,
+    name: String,
+    prefix: String,
+    scopes: String,
+    expires_at: String,
+    last_used_at: Option<String>,
+    revoked_at: Option<String>,
+    created_at: Option<String>,
+}
+
+impl From<ApiKeyRow> for ApiKey {
+    fn from(row: ApiKeyRow) -> Self {
+        ApiKey {
+            id: row.id,
+            name: row.name,
+            prefix: row.prefix,
+            scopes: row.scopes.split_whitespace().map(str::to_string).collect(),
+            expires_at: row.expires_at,
+            last_used_at: row.last_used_at,
+            revoked_at: row.revoked_at,
+            created_at: row.created_at,
+        }
+    }
+}
+
+pub async fn create_api_key(
+    auth_user: AuthUser,
+    State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+    Json(new_key): Json<NewApiKey>,
+) -> Result<Json<CreatedApiKey>, ApiError> {
+    auth_user.require_session()?;
+
+    let name = new_key.name.trim();
+    if name.is_empty() {
+        return Err(ApiError::ValidationError(
+            "API key name is required".to_string(),
+        ));
+    }
+
+    let days = new_key
+        .expires_in_days
+        .unwrap_or(config.api_key_default_days);
+    if days < 1 || days > config.api_key_max_days {
+        return Err(ApiError::ValidationError(format!(
+            "API keys must expire within 1 to {} days",
+            config.api_key_max_days
+        )));
+    }
+
+    // A key can only carry permissions its owner's role has
+    let mut scopes = Vec::new();
+    for scope in &new_key.scopes {
+        let permission = Permission::parse(scope)
+            .ok_or_else(|| ApiError::ValidationError(format!("Unknown scope {scope}")))?;
+        if !has_permission(&pool, auth_user.user_id, permission).await? {
+            return Err(ApiError::AuthorizationError(format!(
+                "Your role does not have the {scope} permission"
+            )));
+        }
+        if !scopes.contains(&scope.as_str()) {
+            scopes.push(scope.as_str());
+        }
+    }
+    let scopes = scopes.join(" ");
+
+    let (key, prefix) = api_keys::generate();
+    let key_hash = hash_token(&key);
+    let expires = format!("+{days} days");
+
+    let api_key = sqlx::query_as!(
+        ApiKeyRow,
+        r#"
+        INSERT INTO api_keys (user_id, name, prefix, key_hash, scopes, expires_at)
+        VALUES (?, ?, ?, ?, ?, datetime('now', ?))
+        RETURNING id as "id!", name, prefix, scopes, expires_at, last_used_at, revoked_at,
+                  created_at
+        "#,
+        auth_user.user_id,
+        name,
+        prefix,
+        key_hash,
+        scopes,
+        expires
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    Ok(Json(CreatedApiKey {
+        api_key: api_key.into(),
+        key,
+    }))
+}
+
+pub async fn list_api_keys(
+    auth_user: AuthUser,
+    State(pool): State<SqlitePool>,
+) -> Result<Json<Vec<ApiKey>>, ApiError> {
+    auth_user.require_session()?;
+
+    let api_keys = sqlx::query_as!(
+        ApiKeyRow,
+        r#"
+        SELECT id as "id!", name, prefix, scopes, expires_at, last_used_at, revoked_at,
+               created_at
+        FROM api_keys
+        WHERE user_id = ?
+        ORDER BY created_at DESC
+        "#,
+        auth_user.user_id
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    Ok(Json(api_keys.into_iter().map(ApiKey::from).collect()))
+}
+
+pub async fn revoke_api_key(
+    auth_user: AuthUser,
+    State(pool): State<SqlitePool>,
+    Path(key_id): Path<i64>,
+) -> Result<Json<()>, ApiError> {
+    auth_user.require_session()?;
+
+    let revoked = sqlx::query!(
+        r#"
+        UPDATE api_keys
+        SET revoked_at = CURRENT_TIMESTAMP
+        WHERE id = ? AND user_id = ? AND revoked_at IS NULL
+        "#,
+        key_id,
+        auth_user.user_id
+    )
+    .execute(&pool)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .rows_affected();
+
+    if revoked == 0 {
+        return Err(ApiError::NotFound);
+    }
+
+    Ok(Json(()))
+}
@@ -0,0 +1,91 @@
+use crate::audit::{self, AuditFilter, AuditPage, AuditPageParams};
+use crate::error::ApiError;
+use axum::{
+    body::Body,
+    extract::{Json, Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use futures_util::stream;
+use sqlx::SqlitePool;
+use std::sync::Arc;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+const EXPORT_BATCH_SIZE: i64 = 500;
+
+pub async fn list_audit_events(
+    State(pool): State<SqlitePool>,
+    Query(filter): Query<AuditFilter>,
+    Query(page): Query<AuditPageParams>,
+) -> Result<Json<AuditPage>, ApiError> {
+    let limit = page.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(ApiError::ValidationError(format!(
+            "limit must be between 1 and {MAX_PAGE_SIZE}"
+        )));
+    }
+
+    // One extra row tells whether there is a next page
+    let mut events = audit::fetch_page(&pool, &filter, page.before_id, limit + 1)
+        .await
+        .map_err(ApiError::DatabaseError)?;
+    let next_cursor = if events.len() as i64 > limit {
+        events.truncate(limit as usize);
+        events.last().map(|event| event.id)
+    } else {
+        None
+    };
+
+    Ok(Json(AuditPage {
+        events,
+        next_cursor,
+    }))
+}
+
+// Streams every matching event as one JSON object per line, reading the
+// table in batches so large exports don't sit in memory
+pub async fn export_audit_events(
+    State(pool): State<SqlitePool>,
+    Query(filter): Query<AuditFilter>,
+) -> Response {
+    let filter = Arc::new(filter);
+    // The cursor is None once the last batch has been sent
+    let start: Option<Option<i64>> = Some(None);
+    let lines = stream::unfold(start, move |cursor| {
+        let pool = pool.clone();
+        let filter = filter.clone();
+        async move {
+            let before_id = cursor?;
+            let events = match audit::fetch_page(&pool, &filter, before_id, EXPORT_BATCH_SIZE).await
+            {
+                Ok(events) => events,
+                Err(err) => return Some((Err(err), None)),
+            };
+            if events.is_empty() {
+                return None;
+            }
+
+            let next = (events.len() as i64 == EXPORT_BATCH_SIZE)
+                .then(|| events.last().map(|event| event.id));
+            let mut chunk = String::new();
+            for event in &events {
+                chunk.push_str(&serde_json::to_string(event).unwrap_or_default());
+                chunk.push('\n');
+            }
+            Some((Ok(chunk), next))
+        }
+    });
+
+    (
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"audit-events.ndjson\"",
+            ),
+        ],
+        Body::from_stream(lines),
+    )
+        .into_response()
+}
@@ -1,17 +1,25 @@
 // HTTP handlers for auth routes (login, password reset, etc.)
 
 use crate::{
-    auth::{self, AuthResponse, Claims},
+    audit::AuditEvent,
+    auth::{self, AuthResponse, RefreshClaims, TokenType},
+    client_info::ClientInfo,
+    config::Config,
+    cookies::{self, AuthMode},
     error::ApiError,
+    keyring::KeyRing,
+    mail::{MailQueue, Template},
     models::{User, UserRole},
+    password, password_policy,
+    tokens::{hash_token, new_token},
 };
-use axum::{extract::State, Json};
-use bcrypt::{hash, verify, DEFAULT_COST};
-use chrono::{DateTime, Utc};
-use jsonwebtoken::{decode, DecodingKey, Validation};
-use serde::{Deserialize, Serialize};
+use axum::{extract::State, http::HeaderMap, Json};
+use axum_extra::extract::cookie::CookieJar;
+use chrono::Utc;
+use jsonwebtoken::jwk::JwkSet;
+use serde::Deserialize;
 use sqlx::SqlitePool;
-use uuid::Uuid;
+use std::sync::Arc;
 
 #[derive(Debug, Deserialize)]
 pub struct PasswordResetRequest {
@@ -26,10 +34,69 @@ pub struct PasswordResetConfirm {
 
 pub async fn request_password_reset(
     State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+    State(mail): State<MailQueue>,
+    client: ClientInfo,
     Json(request): Json<PasswordResetRequest>,
 ) -> Result<Json<()>, ApiError> {
-    // Generate a reset token
-    let reset_token = Uuid::new_v4().to_string();
+    let email = request.email.trim().to_lowercase();
+
+    // Limits are counted per address whether or not an account exists
+    let recent = sqlx::query!(
+        r#"
+        SELECT
+            (SELECT COUNT(*) FROM password_reset_requests
+             WHERE email = ? AND created_at > datetime('now', '-1 hour')) as "by_email!: i64",
+            (SELECT COUNT(*) FROM password_reset_requests
+             WHERE ip_address = ? AND created_at > datetime('now', '-1 hour')) as "by_ip!: i64"
+        "#,
+        email,
+        client.ip_address
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    if recent.by_email >= config.password_reset_limit_per_email
+        || recent.by_ip >= config.password_reset_limit_per_ip
+    {
+        return Err(ApiError::TooManyRequests(
+            "Too many password reset requests, please try again later".to_string(),
+        ));
+    }
+
+    sqlx::query!(
+        r#"
+        INSERT INTO password_reset_requests (email, ip_address)
+        VALUES (?, ?)
+        "#,
+        email,
+        client.ip_address
+    )
+    .execute(&pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    // Look the account up after responding so neither the response nor its
+    // timing tells whether the address is registered
+    tokio::spawn(async move {
+        if let Err(err) = send_password_reset(&pool, &config, &mail, &email).await {
+            tracing::error!("failed to issue password reset: {:?}", err);
+        }
+    });
+
+    Ok(Json(()))
+}
+
+async fn send_password_reset(
+    pool: &SqlitePool,
+    config: &Config,
+    mail: &MailQueue,
+    email: &str,
+) -> Result<(), sqlx::Error> {
+    // Generate a reset token, only its hash is stored
+    let reset_token = new_token();
+    let token_hash = hash_token(&reset_token);
     // Format datetime as string that SQLite can handle
     let expires_at = Utc::now()
         .checked_add_signed(chrono::Duration::hours(1))
@@ -37,39 +104,47 @@ pub async fn request_password_reset(
         .format("%Y-%m-%d %H:%M:%S")
         .to_string();
 
-    sqlx::query!(
+    let updated = sqlx::query!(
         r#"
         UPDATE users 
         SET reset_token = ?, reset_token_expires = ?
-        WHERE email = ?
+        WHERE lower(email) = ?
         "#,
-        reset_token,
+        token_hash,
         expires_at,
-        request.email
+        email
     )
-    .execute(&pool)
-    .await
-    .map_err(ApiError::DatabaseError)?;
+    .execute(pool)
+    .await?
+    .rows_affected();
 
-    // In a real application, you would send this token via email
-    // For now, we'll just return success
-    Ok(Json(()))
+    if updated > 0 {
+        let link = format!("{}/reset-password?token={}", config.app_url, reset_token);
+        mail.enqueue(email, Template::PasswordReset { link })
+            .await?;
+    }
+
+    Ok(())
 }
 
 pub async fn reset_password(
     State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+    State(mail): State<MailQueue>,
+    client: ClientInfo,
     Json(reset): Json<PasswordResetConfirm>,
 ) -> Result<Json<()>, ApiError> {
     let now = Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    let token_hash = hash_token(&reset.token);
 
     // Find user with valid reset token
     let user = sqlx::query!(
         r#"
-        SELECT id 
+        SELECT id, email
         FROM users 
         WHERE reset_token = ? AND reset_token_expires > ?
         "#,
-        reset.token,
+        token_hash,
         now
     )
     .fetch_optional(&pool)
@@ -77,20 +152,55 @@ pub async fn reset_password(
     .map_err(ApiError::DatabaseError)?
     .ok_or_else(|| ApiError::ValidationError("Invalid or expired reset token".to_string()))?;
 
+    password_policy::check(&config, &reset.new_password).await?;
+
     // Hash new password and update user
-    let password_hash = hash(reset.new_password.as_bytes(), DEFAULT_COST)
-        .map_err(|_| ApiError::ValidationError("Password hashing failed".to_string()))?;
+    let password_hash = password::hash(&reset.new_password).await?;
 
-    sqlx::query!(
+    let mut tx = pool.begin().await.map_err(ApiError::DatabaseError)?;
+
+    // The token must still be there, so two concurrent resets cannot both use
+    // it. It was mailed to the address, which proves the user owns it.
+    let updated = sqlx::query!(
         r#"
         UPDATE users 
-        SET password_hash = ?, reset_token = NULL, reset_token_expires = NULL
-        WHERE id = ?
+        SET password_hash = ?, reset_token = NULL, reset_token_expires = NULL,
+            verified = true, updated_at = CURRENT_TIMESTAMP
+        WHERE id = ? AND reset_token = ?
         "#,
         password_hash,
-        user.id
+        user.id,
+        token_hash
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .rows_affected();
+
+    if updated == 0 {
+        return Err(ApiError::ValidationError(
+            "Invalid or expired reset token".to_string(),
+        ));
+    }
+
+    // Whoever knew the old password is logged out
+    auth::revoke_all_sessions(&mut *tx, user.id).await?;
+    AuditEvent::new("auth.password_reset")
+        .by_user(user.id)
+        .target("user", user.id)
+        .record(&mut *tx, &client)
+        .await?;
+    tx.commit().await.map_err(ApiError::DatabaseError)?;
+
+    mail.enqueue(
+        &user.email,
+        Template::Notification {
+            subject: "Your password was changed".to_string(),
+            message: "The password of your yRealEstate account was just reset. \
+                      If this was not you, please contact support immediately."
+                .to_string(),
+        },
     )
-    .execute(&pool)
     .await
     .map_err(ApiError::DatabaseError)?;
 
@@ -102,18 +212,101 @@ pub struct RefreshToken {
     pub token: String,
 }
 
+// In cookie mode the refresh token comes from its cookie and the body can
+// be empty
 pub async fn refresh_token(
     State(pool): State<SqlitePool>,
-    Json(refresh): Json<RefreshToken>,
-) -> Result<Json<AuthResponse>, ApiError> {
+    State(keys): State<Arc<KeyRing>>,
+    State(config): State<Arc<Config>>,
+    mode: AuthMode,
+    client: ClientInfo,
+    headers: HeaderMap,
+    refresh: Option<Json<RefreshToken>>,
+) -> Result<(CookieJar, Json<AuthResponse>), ApiError> {
+    let token = match mode {
+        AuthMode::Cookie => cookies::refresh_token(&headers)?,
+        AuthMode::Bearer => {
+            let Json(refresh) = refresh
+                .ok_or_else(|| ApiError::ValidationError("Missing refresh token".to_string()))?;
+            refresh.token
+        }
+    };
+
     // Validate refresh token
-    let claims = decode::<Claims>(
-        &refresh.token,
-        &DecodingKey::from_secret(auth::JWT_SECRET),
-        &Validation::default(),
+    let claims = keys
+        .decode::<RefreshClaims>(&token)
+        .ok()
+        .filter(|claims| claims.typ == TokenType::Refresh)
+        .ok_or_else(|| ApiError::AuthenticationError("Invalid refresh token".to_string()))?;
+
+    let mut tx = pool.begin().await.map_err(ApiError::DatabaseError)?;
+
+    // Consume the token. Only one request can win this update, and only
+    // while its session is still alive.
+    let consumed = sqlx::query!(
+        r#"
+        UPDATE refresh_tokens
+        SET used_at = CURRENT_TIMESTAMP
+        WHERE id = ? AND user_id = ? AND used_at IS NULL AND revoked_at IS NULL
+        AND EXISTS (
+            SELECT 1 FROM sessions s
+            WHERE s.id = refresh_tokens.family_id AND s.revoked_at IS NULL
+        )
+        "#,
+        claims.jti,
+        claims.sub
     )
-    .map_err(|_| ApiError::AuthenticationError("Invalid refresh token".to_string()))?
-    .claims;
+    .execute(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .rows_affected();
+
+    if consumed == 0 {
+        // A token that was already used is being replayed, so it has leaked.
+        // Revoke the whole family to log out both the thief and the owner.
+        let revoked = sqlx::query!(
+            r#"
+            UPDATE refresh_tokens
+            SET revoked_at = CURRENT_TIMESTAMP
+            WHERE family_id = ? AND revoked_at IS NULL
+            AND EXISTS (
+                SELECT 1 FROM refresh_tokens WHERE id = ? AND used_at IS NOT NULL
+            )
+            "#,
+            claims.fam,
+            claims.jti
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(ApiError::DatabaseError)?
+        .rows_affected();
+
+        if revoked > 0 {
+            sqlx::query!(
+                r#"
+                UPDATE sessions
+                SET revoked_at = CURRENT_TIMESTAMP
+                WHERE id = ? AND revoked_at IS NULL
+                "#,
+                claims.fam
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(ApiError::DatabaseError)?;
+        }
+        tx.commit().await.map_err(ApiError::DatabaseError)?;
+
+        if revoked > 0 {
+            tracing::warn!(
+                "refresh token reuse detected for user {}, revoked family {}",
+                claims.sub,
+                claims.fam
+            );
+        }
+        return Err(ApiError::AuthenticationError(
+            "Invalid refresh token".to_string(),
+        ));
+    }
 
     // Get user
     let user = sqlx::query_as!(
@@ -129,26 +322,50 @@ pub async fn refresh_token(
             verified,
             profile_image_url as "profile_image_url?",
             created_at as "created_at?: String",
-            updated_at as "updated_at?: String",
-            reset_token as "reset_token?",
-            reset_token_expires as "reset_token_expires?: String"
+            updated_at as "updated_at?: String"
         FROM users 
         WHERE id = ?
         "#,
         claims.sub
     )
-    .fetch_optional(&pool)
+    .fetch_optional(&mut *tx)
     .await
     .map_err(ApiError::DatabaseError)?
     .ok_or_else(|| ApiError::AuthenticationError("User not found".to_string()))?;
 
-    // Create new tokens
-    let access_token = auth::create_token(&user)?;
-    let refresh_token = auth::create_refresh_token(&user)?;
+    sqlx::query!(
+        r#"
+        UPDATE sessions
+        SET last_used_at = CURRENT_TIMESTAMP,
+            ip_address = COALESCE(?, ip_address),
+            user_agent = COALESCE(?, user_agent)
+        WHERE id = ?
+        "#,
+        client.ip_address,
+        client.user_agent,
+        claims.fam
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    // Create new tokens, rotating within the same family
+    let access_token = auth::create_token(&keys, &user, &claims.fam)?;
+    let refresh_token = auth::create_refresh_token(&mut *tx, &keys, &user, &claims.fam).await?;
+
+    tx.commit().await.map_err(ApiError::DatabaseError)?;
 
-    Ok(Json(AuthResponse {
+    let mut auth = AuthResponse {
         token: access_token,
         refresh_token,
         user,
-    }))
+    };
+    let jar = mode.deliver(&config, &mut auth);
+
+    Ok((jar, Json(auth)))
+}
+
+// Public keys other services use to verify our access tokens
+pub async fn jwks(State(keys): State<Arc<KeyRing>>) -> Json<JwkSet> {
+    Json(keys.jwks().clone())
 }
@@ -1,12 +1,41 @@
+use crate::access::{property_parties, require_participant};
+use crate::audit::AuditEvent;
+use crate::auth::AuthUser;
+use crate::client_info::ClientInfo;
+use crate::config::Config;
 use crate::error::ApiError;
 use crate::models::{Conversation, ConversationDetails, Message, NewConversation, NewMessage};
+use crate::verification::{require_verified, GatedAction};
 use axum::extract::{Json, Path, State};
+use serde_json::json;
 use sqlx::SqlitePool;
+use std::sync::Arc;
 
 pub async fn create_conversation(
+    auth_user: AuthUser,
     State(pool): State<SqlitePool>,
+    client: ClientInfo,
     Json(new_conv): Json<NewConversation>,
 ) -> Result<Json<Conversation>, ApiError> {
+    // The creator always takes part. Anyone else talks to the listing's
+    // owner or agent, only they can pull in other people.
+    let parties = property_parties(&pool, new_conv.property_id).await?;
+    let mut participant_ids = vec![auth_user.user_id];
+    for user_id in new_conv.participant_ids {
+        if !participant_ids.contains(&user_id) {
+            participant_ids.push(user_id);
+        }
+    }
+    if !parties.is_manager(auth_user.user_id)
+        && participant_ids[1..]
+            .iter()
+            .any(|user_id| !parties.is_manager(*user_id))
+    {
+        return Err(ApiError::AuthorizationError(
+            "Conversations about a listing must include only its owner or agent".to_string(),
+        ));
+    }
+
     let mut tx = pool.begin().await.map_err(ApiError::DatabaseError)?;
 
     let conversation = sqlx::query_as!(
@@ -23,7 +52,7 @@ pub async fn create_conversation(
     .map_err(ApiError::DatabaseError)?;
 
     // Add participants
-    for user_id in new_conv.participant_ids {
+    for user_id in &participant_ids {
         sqlx::query!(
             r#"
             INSERT INTO conversation_participants (conversation_id, user_id)
@@ -37,16 +66,32 @@ pub async fn create_conversation(
         .map_err(ApiError::DatabaseError)?;
     }
 
+    AuditEvent::new("conversation.create")
+        .by(&auth_user)
+        .target("conversation", conversation.id.unwrap())
+        .after(json!({
+            "property_id": conversation.property_id,
+            "participant_ids": participant_ids,
+        }))
+        .record(&mut *tx, &client)
+        .await?;
+
     tx.commit().await.map_err(ApiError::DatabaseError)?;
 
     Ok(Json(conversation))
 }
 
 pub async fn send_message(
+    auth_user: AuthUser,
     State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+    client: ClientInfo,
     Path(conv_id): Path<i64>,
     Json(new_message): Json<NewMessage>,
 ) -> Result<Json<Message>, ApiError> {
+    require_participant(&pool, conv_id, auth_user.user_id).await?;
+    require_verified(&pool, &config, auth_user.user_id, GatedAction::SendMessage).await?;
+
     let message = sqlx::query_as!(
         Message,
         r#"
@@ -55,20 +100,31 @@ pub async fn send_message(
         RETURNING id, conversation_id, sender_id, content, read, created_at
         "#,
         conv_id,
-        new_message.sender_id,
+        auth_user.user_id,
         new_message.content
     )
     .fetch_one(&pool)
     .await
     .map_err(ApiError::DatabaseError)?;
 
+    // The content stays out of the log, it is private to the participants
+    AuditEvent::new("message.send")
+        .by(&auth_user)
+        .target("message", message.id.unwrap())
+        .after(json!({ "conversation_id": conv_id }))
+        .record(&pool, &client)
+        .await?;
+
     Ok(Json(message))
 }
 
 pub async fn get_messages(
+    auth_user: AuthUser,
     State(pool): State<SqlitePool>,
     Path(conv_id): Path<i64>,
 ) -> Result<Json<Vec<Message>>, ApiError> {
+    require_participant(&pool, conv_id, auth_user.user_id).await?;
+
     let messages = sqlx::query_as!(
         Message,
         r#"
@@ -87,9 +143,16 @@ pub async fn get_messages(
 }
 
 pub async fn get_user_conversations(
+    auth_user: AuthUser,
     State(pool): State<SqlitePool>,
     Path(user_id): Path<i64>,
 ) -> Result<Json<Vec<ConversationDetails>>, ApiError> {
+    if user_id != auth_user.user_id {
+        return Err(ApiError::AuthorizationError(
+            "Not allowed to access another user's conversations".to_string(),
+        ));
+    }
+
     let conversations = sqlx::query_as!(
         ConversationDetails,
         r#"
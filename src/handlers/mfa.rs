@@ -0,0 +1,326 @@
+use crate::audit::AuditEvent;
+use crate::auth::{start_session, AuthUser, MfaLoginResponse};
+use crate::client_info::ClientInfo;
+use crate::config::Config;
+use crate::cookies::AuthMode;
+use crate::error::ApiError;
+use crate::keyring::KeyRing;
+use crate::login_attempts::{self, AttemptStore};
+use crate::mail::MailQueue;
+use crate::mfa;
+use crate::models::{
+    MfaChallengeRequest, MfaCodeRequest, MfaEnrollment, MfaLoginRequest, MfaPolicy,
+    MfaPolicyUpdate, RecoveryCodes, User, UserRole,
+};
+use axum::extract::{Json, Path, State};
+use axum_extra::extract::cookie::CookieJar;
+use serde_json::json;
+use sqlx::SqlitePool;
+use std::sync::Arc;
+
+async fn fetch_user(pool: &SqlitePool, user_id: i64) -> Result<User, ApiError> {
+    sqlx::query_as!(
+        User,
+        r#"
+        SELECT id, email, password_hash, full_name, phone,
+               role as "role: UserRole", verified, profile_image_url,
+               created_at, updated_at
+        FROM users
+        WHERE id = ?
+        "#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .ok_or(ApiError::NotFound)
+}
+
+// Second login step. When the role requires MFA and the user has just
+// enrolled, the first valid code also confirms the enrollment. Wrong codes
+// count towards the same lockout as wrong passwords.
+#[allow(clippy::too_many_arguments)]
+pub async fn login_mfa(
+    State(pool): State<SqlitePool>,
+    State(keys): State<Arc<KeyRing>>,
+    State(config): State<Arc<Config>>,
+    State(attempts): State<Arc<dyn AttemptStore>>,
+    State(mail): State<MailQueue>,
+    mode: AuthMode,
+    client: ClientInfo,
+    Json(request): Json<MfaLoginRequest>,
+) -> Result<(CookieJar, Json<MfaLoginResponse>), ApiError> {
+    let challenge = mfa::find_challenge(&pool, &request.challenge_token).await?;
+    let user = fetch_user(&pool, challenge.user_id).await?;
+    let ip = client.ip_address.as_deref();
+    login_attempts::check(attempts.as_ref(), &user.email, ip).await?;
+
+    let (accepted, recovery_codes) = if mfa::is_enabled(&pool, challenge.user_id).await? {
+        let accepted = mfa::verify(&pool, challenge.user_id, &request.code).await?;
+        (accepted, None)
+    } else {
+        let codes = mfa::confirm_enrollment(&pool, challenge.user_id, &request.code).await?;
+        (codes.is_some(), codes)
+    };
+
+    if !accepted {
+        mfa::record_failed_attempt(&pool, challenge.id).await?;
+        AuditEvent::new("auth.mfa_failed")
+            .by_user(challenge.user_id)
+            .target("user", challenge.user_id)
+            .record(&pool, &client)
+            .await?;
+        let locked =
+            login_attempts::record_failure(attempts.as_ref(), &config, &user.email, ip).await?;
+        if locked {
+            login_attempts::notify_locked(&mail, &config, &user.email).await?;
+        }
+        return Err(ApiError::AuthenticationError(
+            "Invalid verification code".to_string(),
+        ));
+    }
+    if !mfa::complete_challenge(&pool, challenge.id).await? {
+        return Err(ApiError::AuthenticationError(
+            "Invalid or expired MFA challenge".to_string(),
+        ));
+    }
+
+    login_attempts::clear_account(attempts.as_ref(), &user.email).await?;
+
+    let user_id = challenge.user_id;
+    let mut auth = start_session(&pool, &keys, user, &client).await?;
+    AuditEvent::new("auth.login")
+        .by_user(user_id)
+        .target("user", user_id)
+        .after(json!({ "method": "mfa" }))
+        .record(&pool, &client)
+        .await?;
+    let jar = mode.deliver(&config, &mut auth);
+
+    Ok((
+        jar,
+        Json(MfaLoginResponse {
+            auth,
+            recovery_codes,
+        }),
+    ))
+}
+
+// Lets a user whose role requires MFA enroll before they have a session
+pub async fn login_mfa_enroll(
+    State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+    Json(request): Json<MfaChallengeRequest>,
+) -> Result<Json<MfaEnrollment>, ApiError> {
+    let challenge = mfa::find_challenge(&pool, &request.challenge_token).await?;
+    let user = fetch_user(&pool, challenge.user_id).await?;
+
+    let enrollment =
+        mfa::start_enrollment(&pool, &config.mfa_issuer, challenge.user_id, &user.email).await?;
+
+    Ok(Json(enrollment))
+}
+
+pub async fn enroll_mfa(
+    auth_user: AuthUser,
+    State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+) -> Result<Json<MfaEnrollment>, ApiError> {
+    auth_user.require_session()?;
+    let user = fetch_user(&pool, auth_user.user_id).await?;
+
+    let enrollment =
+        mfa::start_enrollment(&pool, &config.mfa_issuer, auth_user.user_id, &user.email).await?;
+
+    Ok(Json(enrollment))
+}
+
+pub async fn confirm_mfa(
+    auth_user: AuthUser,
+    State(pool): State<SqlitePool>,
+    client: ClientInfo,
+    Json(request): Json<MfaCodeRequest>,
+) -> Result<Json<RecoveryCodes>, ApiError> {
+    auth_user.require_session()?;
+    let recovery_codes = mfa::confirm_enrollment(&pool, auth_user.user_id, &request.code)
+        .await?
+        .ok_or_else(|| ApiError::ValidationError("Invalid verification code".to_string()))?;
+    AuditEvent::new("mfa.enable")
+        .by(&auth_user)
+        .target("user", auth_user.user_id)
+        .record(&pool, &client)
+        .await?;
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+// Checks a code sent with a signed-in request. Wrong codes count towards
+// the same lockout as the login challenge, so a hijacked session can't
+// guess them either.
+async fn verify_code(
+    pool: &SqlitePool,
+    config: &Config,
+    attempts: &dyn AttemptStore,
+    mail: &MailQueue,
+    client: &ClientInfo,
+    user: &User,
+    code: &str,
+) -> Result<(), ApiError> {
+    let user_id = user.id.unwrap();
+    let ip = client.ip_address.as_deref();
+    login_attempts::check(attempts, &user.email, ip).await?;
+
+    if !mfa::verify(pool, user_id, code).await? {
+        AuditEvent::new("auth.mfa_failed")
+            .by_user(user_id)
+            .target("user", user_id)
+            .record(pool, client)
+            .await?;
+        let locked = login_attempts::record_failure(attempts, config, &user.email, ip).await?;
+        if locked {
+            login_attempts::notify_locked(mail, config, &user.email).await?;
+        }
+        return Err(ApiError::ValidationError(
+            "Invalid verification code".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn regenerate_recovery_codes(
+    auth_user: AuthUser,
+    State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+    State(attempts): State<Arc<dyn AttemptStore>>,
+    State(mail): State<MailQueue>,
+    client: ClientInfo,
+    Json(request): Json<MfaCodeRequest>,
+) -> Result<Json<RecoveryCodes>, ApiError> {
+    auth_user.require_session()?;
+    let user = fetch_user(&pool, auth_user.user_id).await?;
+    verify_code(
+        &pool,
+        &config,
+        attempts.as_ref(),
+        &mail,
+        &client,
+        &user,
+        &request.code,
+    )
+    .await?;
+
+    let recovery_codes = mfa::regenerate_recovery_codes(&pool, auth_user.user_id).await?;
+    AuditEvent::new("mfa.regenerate_recovery_codes")
+        .by(&auth_user)
+        .target("user", auth_user.user_id)
+        .record(&pool, &client)
+        .await?;
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn disable_mfa(
+    auth_user: AuthUser,
+    State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+    State(attempts): State<Arc<dyn AttemptStore>>,
+    State(mail): State<MailQueue>,
+    client: ClientInfo,
+    Json(request): Json<MfaCodeRequest>,
+) -> Result<Json<()>, ApiError> {
+    auth_user.require_session()?;
+    // The token's role may predate a role change
+    let user = fetch_user(&pool, auth_user.user_id).await?;
+    if mfa::is_required_for_role(&pool, &user.role.to_string()).await? {
+        return Err(ApiError::AuthorizationError(
+            "Two-factor authentication is required for your role".to_string(),
+        ));
+    }
+    verify_code(
+        &pool,
+        &config,
+        attempts.as_ref(),
+        &mail,
+        &client,
+        &user,
+        &request.code,
+    )
+    .await?;
+
+    mfa::disable(&pool, auth_user.user_id).await?;
+    AuditEvent::new("mfa.disable")
+        .by(&auth_user)
+        .target("user", auth_user.user_id)
+        .record(&pool, &client)
+        .await?;
+
+    Ok(Json(()))
+}
+
+pub async fn list_mfa_policies(
+    State(pool): State<SqlitePool>,
+) -> Result<Json<Vec<MfaPolicy>>, ApiError> {
+    let policies = sqlx::query_as!(
+        MfaPolicy,
+        r#"
+        SELECT role as "role!", required as "required: bool", updated_at
+        FROM mfa_role_policies
+        ORDER BY role
+        "#
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    Ok(Json(policies))
+}
+
+// Requiring MFA takes effect at the next login of each user with the role
+pub async fn update_mfa_policy(
+    auth_user: AuthUser,
+    State(pool): State<SqlitePool>,
+    client: ClientInfo,
+    Path(role): Path<UserRole>,
+    Json(update): Json<MfaPolicyUpdate>,
+) -> Result<Json<MfaPolicy>, ApiError> {
+    let role = role.to_string();
+
+    let mut tx = pool.begin().await.map_err(ApiError::DatabaseError)?;
+    let previous = sqlx::query_scalar!(
+        r#"SELECT required as "required: bool" FROM mfa_role_policies WHERE role = ?"#,
+        role
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    let policy = sqlx::query_as!(
+        MfaPolicy,
+        r#"
+        INSERT INTO mfa_role_policies (role, required)
+        VALUES (?, ?)
+        ON CONFLICT (role) DO UPDATE
+        SET required = excluded.required, updated_at = CURRENT_TIMESTAMP
+        RETURNING role as "role!", required as "required: bool", updated_at
+        "#,
+        role,
+        update.required
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    AuditEvent::new("mfa.update_policy")
+        .by(&auth_user)
+        .target("role", &role)
+        .before(json!({ "required": previous.unwrap_or(false) }))
+        .after(json!({ "required": policy.required }))
+        .record(&mut *tx, &client)
+        .await?;
+    tx.commit().await.map_err(ApiError::DatabaseError)?;
+
+    Ok(Json(policy))
+}
@@ -1,9 +1,21 @@
+mod account;
+mod api_keys;
+mod audit;
 mod authentication;
 mod messages;
+mod mfa;
+mod oidc;
 mod properties;
+mod sessions;
 mod users;
 
+pub use account::*;
+pub use api_keys::*;
+pub use audit::*;
 pub use authentication::*;
 pub use messages::*;
+pub use mfa::*;
+pub use oidc::*;
 pub use properties::*;
+pub use sessions::*;
 pub use users::*;
@@ -0,0 +1,86 @@
+use crate::audit::AuditEvent;
+use crate::auth::{finish_login, AuthUser, LoginResponse};
+use crate::client_info::ClientInfo;
+use crate::config::Config;
+use crate::cookies::AuthMode;
+use crate::error::ApiError;
+use crate::keyring::KeyRing;
+use crate::login_attempts::{self, AttemptStore};
+use crate::models::{OidcAuthorization, OidcCallback};
+use crate::oidc::{self, OidcClient};
+use axum::extract::{Json, Path, State};
+use axum_extra::extract::cookie::CookieJar;
+use serde_json::json;
+use sqlx::SqlitePool;
+use std::sync::Arc;
+
+pub async fn oidc_authorize(
+    State(pool): State<SqlitePool>,
+    State(oidc): State<Arc<OidcClient>>,
+    Path(provider): Path<String>,
+) -> Result<Json<OidcAuthorization>, ApiError> {
+    let authorization_url = oidc.authorization_url(&pool, &provider).await?;
+
+    Ok(Json(OidcAuthorization { authorization_url }))
+}
+
+// The frontend posts the code and state it got on its redirect URI
+#[allow(clippy::too_many_arguments)]
+pub async fn oidc_callback(
+    State(pool): State<SqlitePool>,
+    State(keys): State<Arc<KeyRing>>,
+    State(oidc): State<Arc<OidcClient>>,
+    State(config): State<Arc<Config>>,
+    State(attempts): State<Arc<dyn AttemptStore>>,
+    Path(provider): Path<String>,
+    mode: AuthMode,
+    client: ClientInfo,
+    Json(callback): Json<OidcCallback>,
+) -> Result<(CookieJar, Json<LoginResponse>), ApiError> {
+    let claims = oidc
+        .complete(&pool, &provider, &callback.code, &callback.state)
+        .await?;
+    // Checked before an account gets created or linked. A locked account
+    // gets no new MFA challenge either.
+    let ip = client.ip_address.as_deref();
+    if let Some(email) = claims.email.as_deref() {
+        login_attempts::check(attempts.as_ref(), email.trim(), ip).await?;
+    }
+    let user = oidc::find_or_create_user(&pool, &provider, &claims).await?;
+    // An identity linked earlier may belong to an account with another email
+    if claims.email.as_deref().map(str::trim) != Some(user.email.as_str()) {
+        login_attempts::check(attempts.as_ref(), &user.email, ip).await?;
+    }
+
+    let method = json!({ "method": "oidc", "provider": provider });
+    let mut response = finish_login(&pool, &keys, user, &client, method).await?;
+    let jar = mode.deliver_login(&config, &mut response);
+
+    Ok((jar, Json(response)))
+}
+
+// Same callback, but links the provider to the signed-in account instead of
+// signing in. This is the only way to add a provider to an admin account.
+pub async fn oidc_link(
+    auth_user: AuthUser,
+    State(pool): State<SqlitePool>,
+    State(oidc): State<Arc<OidcClient>>,
+    Path(provider): Path<String>,
+    client: ClientInfo,
+    Json(callback): Json<OidcCallback>,
+) -> Result<Json<()>, ApiError> {
+    auth_user.require_session()?;
+    let claims = oidc
+        .complete(&pool, &provider, &callback.code, &callback.state)
+        .await?;
+    oidc::link_identity(&pool, auth_user.user_id, &provider, &claims).await?;
+
+    AuditEvent::new("user.link_identity")
+        .by(&auth_user)
+        .target("user", auth_user.user_id)
+        .after(json!({ "provider": provider }))
+        .record(&pool, &client)
+        .await?;
+
+    Ok(Json(()))
+}
@@ -1,53 +1,86 @@
-use crate::models::{ListingType, Property, PropertyStatus, PropertyType};
+use crate::access::{self, PropertyParties};
+use crate::audit::AuditEvent;
+use crate::auth::{AnyCredential, AuthUser};
+use crate::client_info::ClientInfo;
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::etag;
+use crate::geo::Point;
+use crate::models::{
+    FeatureCollection, ListingType, Property, PropertyPage, PropertyPatch, PropertyStatus,
+    PropertyType,
+};
+use crate::property_map;
+use crate::property_search::{self, PropertySearch};
+use crate::verification::{require_verified, GatedAction};
 use axum::{
-    extract::{Json, Path, State},
-    http::StatusCode,
+    extract::{rejection::QueryRejection, Json, Path, Query, RawQuery, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
 };
 use sqlx::SqlitePool;
+use std::sync::Arc;
 
 pub async fn list_properties(
     State(pool): State<SqlitePool>,
-) -> Result<Json<Vec<Property>>, StatusCode> {
-    let properties = sqlx::query_as!(
-        Property,
-        r#"
-        SELECT 
-            id, title, price, description, location, 
-            bedrooms, bathrooms, square_feet,
-            property_type as "property_type: PropertyType",
-            listing_type as "listing_type: ListingType",
-            status as "status: PropertyStatus",
-            owner_id, agent_id, created_at, updated_at
-        FROM properties
-        ORDER BY created_at DESC
-        "#
-    )
-    .fetch_all(&pool)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    search: Result<Query<PropertySearch>, QueryRejection>,
+) -> Result<Json<PropertyPage>, ApiError> {
+    let Query(search) = search.map_err(|err| ApiError::ValidationError(err.body_text()))?;
+
+    Ok(Json(property_search::search(&pool, &search).await?))
+}
+
+// GeoJSON for the map view, taking the list's filters plus a required
+// bbox and the map's zoom level
+pub async fn properties_map(
+    State(pool): State<SqlitePool>,
+    RawQuery(query): RawQuery,
+) -> Result<Json<FeatureCollection>, ApiError> {
+    let (zoom, search) = property_map::parse_query(query.as_deref().unwrap_or_default())?;
+    let cells = property_search::map_cells(&pool, &search, zoom).await?;
 
-    Ok(Json(properties))
+    Ok(Json(property_map::feature_collection(cells)))
 }
 
 pub async fn create_property(
+    auth_user: AuthUser,
     State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+    client: ClientInfo,
     Json(property): Json<Property>,
-) -> Result<Json<Property>, StatusCode> {
+) -> Result<Json<Property>, ApiError> {
+    require_verified(
+        &pool,
+        &config,
+        auth_user.user_id,
+        GatedAction::CreateListing,
+    )
+    .await?;
+
+    if let Some(agent_id) = property.agent_id {
+        access::require_agent(&pool, agent_id).await?;
+    }
+    property_search::reject_markers(&property)?;
+    let position = Point::from_parts(property.latitude, property.longitude)?;
+    let [geo_x, geo_y, geo_z] = position
+        .map(Point::unit_vector)
+        .map_or([None; 3], |v| v.map(Some));
+
     let created_property = sqlx::query_as!(
         Property,
         r#"
         INSERT INTO properties (
             title, price, description, location,
             bedrooms, bathrooms, square_feet,
-            property_type, listing_type, status
+            property_type, listing_type, status,
+            owner_id, agent_id, latitude, longitude, geo_x, geo_y, geo_z
         )
-        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
         RETURNING id, title, price, description, location,
             bedrooms, bathrooms, square_feet,
             property_type as "property_type: PropertyType",
             listing_type as "listing_type: ListingType",
             status as "status: PropertyStatus",
-            owner_id, agent_id, created_at, updated_at
+            owner_id, agent_id, latitude, longitude, created_at, updated_at, version
         "#,
         property.title,
         property.price,
@@ -58,11 +91,25 @@ pub async fn create_property(
         property.square_feet,
         property.property_type,
         property.listing_type,
-        property.status
+        property.status,
+        auth_user.user_id,
+        property.agent_id,
+        property.latitude,
+        property.longitude,
+        geo_x,
+        geo_y,
+        geo_z
     )
     .fetch_one(&pool)
     .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .map_err(ApiError::DatabaseError)?;
+
+    AuditEvent::new("property.create")
+        .by(&auth_user)
+        .target("property", created_property.id.unwrap())
+        .after(&created_property)
+        .record(&pool, &client)
+        .await?;
 
     Ok(Json(created_property))
 }
@@ -70,8 +117,199 @@ pub async fn create_property(
 pub async fn get_property(
     State(pool): State<SqlitePool>,
     Path(id): Path<i64>,
-) -> Result<Json<Property>, StatusCode> {
-    let property = sqlx::query_as!(
+) -> Result<Tagged<Property>, ApiError> {
+    let property = fetch_property(&pool, id).await?;
+
+    Ok(tagged(property))
+}
+
+// Replaces every field of the listing; the owner stays the same
+pub async fn update_property(
+    AnyCredential(auth_user): AnyCredential,
+    State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+    client: ClientInfo,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+    Json(property): Json<Property>,
+) -> Result<Tagged<Property>, ApiError> {
+    let current = fetch_property(&pool, id).await?;
+
+    save_property(
+        &pool, &config, &auth_user, &client, &headers, current, property,
+    )
+    .await
+}
+
+pub async fn patch_property(
+    AnyCredential(auth_user): AnyCredential,
+    State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+    client: ClientInfo,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+    Json(patch): Json<PropertyPatch>,
+) -> Result<Tagged<Property>, ApiError> {
+    let current = fetch_property(&pool, id).await?;
+    let mut property = current.clone();
+    patch
+        .apply(&mut property)
+        .map_err(|field| ApiError::ValidationError(format!("{field} can't be null")))?;
+
+    save_property(
+        &pool, &config, &auth_user, &client, &headers, current, property,
+    )
+    .await
+}
+
+// Soft delete: the listing disappears from reads and searches until it
+// is restored. The response carries the deleted row's ETag for the
+// restore. Unlike the other writes this needs no verified email, so
+// anyone can always take their listing down.
+pub async fn delete_property(
+    AnyCredential(auth_user): AnyCredential,
+    State(pool): State<SqlitePool>,
+    client: ClientInfo,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, [(HeaderName, HeaderValue); 1]), ApiError> {
+    let current = fetch_property(&pool, id).await?;
+    let parties = PropertyParties {
+        owner_id: current.owner_id,
+        agent_id: current.agent_id,
+    };
+    access::property_access(&pool, &auth_user, &parties).await?;
+    etag::require_match(&headers, current.version)?;
+
+    let mut tx = pool.begin().await.map_err(ApiError::DatabaseError)?;
+
+    let version = sqlx::query_scalar!(
+        r#"
+        UPDATE properties
+        SET deleted_at = CURRENT_TIMESTAMP,
+            version = version + 1,
+            updated_at = CURRENT_TIMESTAMP
+        WHERE id = ? AND version = ? AND deleted_at IS NULL
+        RETURNING version
+        "#,
+        id,
+        current.version
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .ok_or_else(changed_meanwhile)?;
+
+    AuditEvent::new("property.delete")
+        .by(&auth_user)
+        .target("property", id)
+        .before(&current)
+        .record(&mut *tx, &client)
+        .await?;
+
+    tx.commit().await.map_err(ApiError::DatabaseError)?;
+
+    Ok((
+        StatusCode::NO_CONTENT,
+        [(header::ETAG, etag::etag(version))],
+    ))
+}
+
+// Restoring puts the listing back in front of buyers, so it needs the same
+// verified email as creating one
+pub async fn restore_property(
+    AnyCredential(auth_user): AnyCredential,
+    State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+    client: ClientInfo,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+) -> Result<Tagged<Property>, ApiError> {
+    let listing = sqlx::query!(
+        r#"
+        SELECT owner_id, agent_id, version, deleted_at IS NOT NULL as "deleted: bool"
+        FROM properties
+        WHERE id = ?
+        "#,
+        id
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .ok_or(ApiError::NotFound)?;
+    let parties = PropertyParties {
+        owner_id: listing.owner_id,
+        agent_id: listing.agent_id,
+    };
+    access::property_access(&pool, &auth_user, &parties).await?;
+    require_verified(
+        &pool,
+        &config,
+        auth_user.user_id,
+        GatedAction::CreateListing,
+    )
+    .await?;
+    if !listing.deleted {
+        return Err(ApiError::ValidationError(
+            "Listing is not deleted".to_string(),
+        ));
+    }
+    etag::require_match(&headers, listing.version)?;
+
+    let mut tx = pool.begin().await.map_err(ApiError::DatabaseError)?;
+
+    let restored = sqlx::query_as!(
+        Property,
+        r#"
+        UPDATE properties
+        SET deleted_at = NULL,
+            version = version + 1,
+            updated_at = CURRENT_TIMESTAMP
+        WHERE id = ? AND version = ? AND deleted_at IS NOT NULL
+        RETURNING id, title, price, description, location,
+            bedrooms, bathrooms, square_feet,
+            property_type as "property_type: PropertyType",
+            listing_type as "listing_type: ListingType",
+            status as "status: PropertyStatus",
+            owner_id, agent_id, latitude, longitude, created_at, updated_at, version
+        "#,
+        id,
+        listing.version
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .ok_or_else(changed_meanwhile)?;
+
+    AuditEvent::new("property.restore")
+        .by(&auth_user)
+        .target("property", id)
+        .after(&restored)
+        .record(&mut *tx, &client)
+        .await?;
+
+    tx.commit().await.map_err(ApiError::DatabaseError)?;
+
+    Ok(tagged(restored))
+}
+
+// A listing with its version as the ETag header
+type Tagged<T> = ([(HeaderName, HeaderValue); 1], Json<T>);
+
+fn tagged(property: Property) -> Tagged<Property> {
+    (
+        [(header::ETAG, etag::etag(property.version))],
+        Json(property),
+    )
+}
+
+fn changed_meanwhile() -> ApiError {
+    ApiError::PreconditionFailed("The listing was changed since it was read".to_string())
+}
+
+// Deleted listings are not found
+async fn fetch_property(pool: &SqlitePool, id: i64) -> Result<Property, ApiError> {
+    sqlx::query_as!(
         Property,
         r#"
         SELECT id, title, price, description, location,
@@ -79,16 +317,108 @@ pub async fn get_property(
                property_type as "property_type: PropertyType",
                listing_type as "listing_type: ListingType",
                status as "status: PropertyStatus",
-               owner_id, agent_id, created_at, updated_at
+               owner_id, agent_id, latitude, longitude, created_at, updated_at, version
         FROM properties
-        WHERE id = ?
+        WHERE id = ? AND deleted_at IS NULL
         "#,
         id
     )
-    .fetch_optional(&pool)
+    .fetch_optional(pool)
     .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-    .ok_or(StatusCode::NOT_FOUND)?;
+    .map_err(ApiError::DatabaseError)?
+    .ok_or(ApiError::NotFound)
+}
+
+// Shared by PUT and PATCH once the new state of the listing is known
+#[allow(clippy::too_many_arguments)]
+async fn save_property(
+    pool: &SqlitePool,
+    config: &Config,
+    auth_user: &AuthUser,
+    client: &ClientInfo,
+    headers: &HeaderMap,
+    current: Property,
+    property: Property,
+) -> Result<Tagged<Property>, ApiError> {
+    let parties = PropertyParties {
+        owner_id: current.owner_id,
+        agent_id: current.agent_id,
+    };
+    let access = access::property_access(pool, auth_user, &parties).await?;
+    require_verified(pool, config, auth_user.user_id, GatedAction::CreateListing).await?;
+    if property.agent_id != current.agent_id {
+        if !access.may_reassign() {
+            return Err(ApiError::AuthorizationError(
+                "Only the owner can change the listing's agent".to_string(),
+            ));
+        }
+        if let Some(agent_id) = property.agent_id {
+            access::require_agent(pool, agent_id).await?;
+        }
+    }
+    etag::require_match(headers, current.version)?;
+
+    property_search::reject_markers(&property)?;
+    let position = Point::from_parts(property.latitude, property.longitude)?;
+    let [geo_x, geo_y, geo_z] = position
+        .map(Point::unit_vector)
+        .map_or([None; 3], |v| v.map(Some));
+    let id = current.id;
+
+    let mut tx = pool.begin().await.map_err(ApiError::DatabaseError)?;
+
+    // The version check makes concurrent writers from the same read lose
+    let saved = sqlx::query_as!(
+        Property,
+        r#"
+        UPDATE properties
+        SET title = ?, price = ?, description = ?, location = ?,
+            bedrooms = ?, bathrooms = ?, square_feet = ?,
+            property_type = ?, listing_type = ?, status = ?, agent_id = ?,
+            latitude = ?, longitude = ?, geo_x = ?, geo_y = ?, geo_z = ?,
+            version = version + 1,
+            updated_at = CURRENT_TIMESTAMP
+        WHERE id = ? AND version = ? AND deleted_at IS NULL
+        RETURNING id, title, price, description, location,
+            bedrooms, bathrooms, square_feet,
+            property_type as "property_type: PropertyType",
+            listing_type as "listing_type: ListingType",
+            status as "status: PropertyStatus",
+            owner_id, agent_id, latitude, longitude, created_at, updated_at, version
+        "#,
+        property.title,
+        property.price,
+        property.description,
+        property.location,
+        property.bedrooms,
+        property.bathrooms,
+        property.square_feet,
+        property.property_type,
+        property.listing_type,
+        property.status,
+        property.agent_id,
+        property.latitude,
+        property.longitude,
+        geo_x,
+        geo_y,
+        geo_z,
+        id,
+        current.version
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .ok_or_else(changed_meanwhile)?;
+
+    AuditEvent::new("property.update")
+        .by(auth_user)
+        .target("property", id.unwrap())
+        .before(&current)
+        .after(&saved)
+        .record(&mut *tx, client)
+        .await?;
+
+    tx.commit().await.map_err(ApiError::DatabaseError)?;
 
-    Ok(Json(property))
+    Ok(tagged(saved))
 }
@@ -0,0 +1,112 @@
+use crate::audit::AuditEvent;
+use crate::auth::{revoke_all_sessions, AuthUser};
+use crate::client_info::ClientInfo;
+use crate::config::Config;
+use crate::cookies;
+use crate::error::ApiError;
+use crate::models::Session;
+use axum::extract::{Json, Path, State};
+use axum_extra::extract::cookie::CookieJar;
+use serde_json::json;
+use sqlx::SqlitePool;
+use std::sync::Arc;
+
+pub async fn logout(
+    auth_user: AuthUser,
+    State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+) -> Result<(CookieJar, Json<()>), ApiError> {
+    let session_id = auth_user.require_session()?;
+
+    sqlx::query!(
+        r#"
+        UPDATE sessions
+        SET revoked_at = CURRENT_TIMESTAMP
+        WHERE id = ? AND revoked_at IS NULL
+        "#,
+        session_id
+    )
+    .execute(&pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    Ok((cookies::clear(&config), Json(())))
+}
+
+pub async fn logout_all(
+    auth_user: AuthUser,
+    State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+    client: ClientInfo,
+) -> Result<(CookieJar, Json<()>), ApiError> {
+    auth_user.require_session()?;
+    revoke_all_sessions(&pool, auth_user.user_id).await?;
+    AuditEvent::new("session.revoke_all")
+        .by(&auth_user)
+        .target("user", auth_user.user_id)
+        .record(&pool, &client)
+        .await?;
+
+    Ok((cookies::clear(&config), Json(())))
+}
+
+pub async fn list_sessions(
+    auth_user: AuthUser,
+    State(pool): State<SqlitePool>,
+) -> Result<Json<Vec<Session>>, ApiError> {
+    let session_id = auth_user.require_session()?;
+
+    let sessions = sqlx::query_as!(
+        Session,
+        r#"
+        SELECT id as "id!", user_agent, ip_address, created_at, last_used_at,
+               id = ? as "current!: bool"
+        FROM sessions
+        WHERE user_id = ? AND revoked_at IS NULL
+        ORDER BY last_used_at DESC
+        "#,
+        session_id,
+        auth_user.user_id
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    Ok(Json(sessions))
+}
+
+pub async fn revoke_session(
+    auth_user: AuthUser,
+    State(pool): State<SqlitePool>,
+    client: ClientInfo,
+    Path(session_id): Path<String>,
+) -> Result<Json<()>, ApiError> {
+    auth_user.require_session()?;
+
+    let revoked = sqlx::query!(
+        r#"
+        UPDATE sessions
+        SET revoked_at = CURRENT_TIMESTAMP
+        WHERE id = ? AND user_id = ? AND revoked_at IS NULL
+        "#,
+        session_id,
+        auth_user.user_id
+    )
+    .execute(&pool)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .rows_affected();
+
+    if revoked == 0 {
+        return Err(ApiError::NotFound);
+    }
+
+    AuditEvent::new("session.revoke")
+        .by(&auth_user)
+        .target("user", auth_user.user_id)
+        .after(json!({ "session_id": session_id }))
+        .record(&pool, &client)
+        .await?;
+
+    Ok(Json(()))
+}
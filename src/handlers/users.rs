@@ -1,18 +1,50 @@
-use crate::auth::{create_refresh_token, create_token, AuthResponse, AuthUser};
+use crate::access::require_self_or;
+use crate::audit::AuditEvent;
+use crate::auth::{
+    create_impersonation_token, finish_login, revoke_all_sessions, AuthUser, ImpersonationResponse,
+    LoginResponse, Permission,
+};
+use crate::client_info::ClientInfo;
+use crate::config::Config;
+use crate::cookies::AuthMode;
 use crate::error::ApiError;
-use crate::models::{LoginCredentials, NewUser, User, UserRole};
-use axum::{
-    extract::{Json, Path, State},
-    http::StatusCode,
+use crate::keyring::KeyRing;
+use crate::login_attempts::{self, AttemptStore};
+use crate::mail::MailQueue;
+use crate::models::{LoginCredentials, NewUser, RoleUpdate, User, UserRole, VerifyEmailRequest};
+use crate::password;
+use crate::password_policy;
+use crate::tokens::hash_token;
+use crate::verification::{
+    deliver_verification, issue_verification, send_verification, VerificationSender,
 };
-use bcrypt::{hash, verify, DEFAULT_COST};
+use axum::extract::{Json, Path, State};
+use axum_extra::extract::cookie::CookieJar;
+use serde_json::json;
 use sqlx::SqlitePool;
+use std::sync::Arc;
 pub async fn create_user(
     State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+    State(sender): State<Arc<dyn VerificationSender>>,
+    client: ClientInfo,
     Json(new_user): Json<NewUser>,
-) -> Result<Json<User>, StatusCode> {
-    let password_hash = hash(new_user.password.as_bytes(), DEFAULT_COST)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<Json<User>, ApiError> {
+    // Admins are promoted by another admin, never self-registered
+    if new_user.role == UserRole::Admin {
+        return Err(ApiError::AuthorizationError(
+            "Admin accounts can't be registered".to_string(),
+        ));
+    }
+
+    // Stored lowercase so lookups needn't care how the address was typed
+    let email = new_user.email.trim().to_lowercase();
+    password_policy::check(&config, &new_user.password).await?;
+    let password_hash = password::hash(&new_user.password).await?;
+
+    // The account and its first verification link are created together,
+    // so a failure leaves the address free to register again
+    let mut tx = pool.begin().await.map_err(ApiError::DatabaseError)?;
 
     let user = sqlx::query_as!(
         User,
@@ -20,35 +52,163 @@ pub async fn create_user(
         INSERT INTO users (email, password_hash, full_name, phone, role, verified)
         VALUES (?, ?, ?, ?, ?, false)
         RETURNING id, email, password_hash, full_name, phone, role as "role: UserRole", 
-                  verified, profile_image_url, created_at, updated_at,
-            reset_token as "reset_token?",
-            reset_token_expires as "reset_token_expires?: String"
+                  verified, profile_image_url, created_at, updated_at
         "#,
-        new_user.email,
+        email,
         password_hash,
         new_user.full_name,
         new_user.phone,
         new_user.role,
     )
-    .fetch_one(&pool)
+    .fetch_one(&mut *tx)
     .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .map_err(|err| match err {
+        sqlx::Error::Database(db) if db.is_unique_violation() => {
+            ApiError::ValidationError("Email address is already in use".to_string())
+        }
+        err => ApiError::DatabaseError(err),
+    })?;
+
+    let user_id = user.id.unwrap();
+    AuditEvent::new("user.create")
+        .by_user(user_id)
+        .target("user", user_id)
+        .after(json!({ "email": user.email, "role": user.role }))
+        .record(&mut *tx, &client)
+        .await?;
+
+    let token = issue_verification(&mut *tx, user_id).await?;
+
+    tx.commit().await.map_err(ApiError::DatabaseError)?;
+
+    deliver_verification(&config, sender.as_ref(), &user.email, &token).await;
 
     Ok(Json(user))
 }
 
+pub async fn verify_email(
+    State(pool): State<SqlitePool>,
+    Json(request): Json<VerifyEmailRequest>,
+) -> Result<Json<()>, ApiError> {
+    let token_hash = hash_token(&request.token);
+    let mut tx = pool.begin().await.map_err(ApiError::DatabaseError)?;
+
+    let verification = sqlx::query!(
+        r#"
+        UPDATE email_verifications
+        SET used_at = CURRENT_TIMESTAMP
+        WHERE token_hash = ? AND used_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+        RETURNING user_id
+        "#,
+        token_hash
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .ok_or_else(|| {
+        ApiError::ValidationError("Invalid or expired verification token".to_string())
+    })?;
+
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET verified = true, updated_at = CURRENT_TIMESTAMP
+        WHERE id = ?
+        "#,
+        verification.user_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    // Older links for the same user are not needed anymore
+    sqlx::query!(
+        r#"
+        UPDATE email_verifications
+        SET used_at = CURRENT_TIMESTAMP
+        WHERE user_id = ? AND used_at IS NULL
+        "#,
+        verification.user_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    tx.commit().await.map_err(ApiError::DatabaseError)?;
+
+    Ok(Json(()))
+}
+
+pub async fn resend_verification(
+    auth_user: AuthUser,
+    State(pool): State<SqlitePool>,
+    State(config): State<Arc<Config>>,
+    State(sender): State<Arc<dyn VerificationSender>>,
+) -> Result<Json<()>, ApiError> {
+    let user = sqlx::query!(
+        r#"
+        SELECT email, verified
+        FROM users
+        WHERE id = ?
+        "#,
+        auth_user.user_id
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .ok_or(ApiError::NotFound)?;
+
+    if user.verified == Some(true) {
+        return Err(ApiError::ValidationError(
+            "Email address is already verified".to_string(),
+        ));
+    }
+
+    let cooldown = format!("-{} seconds", config.verification_resend_cooldown);
+    let recent = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*)
+        FROM email_verifications
+        WHERE user_id = ? AND created_at > datetime('now', ?)
+        "#,
+        auth_user.user_id,
+        cooldown
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    if recent > 0 {
+        return Err(ApiError::TooManyRequests(
+            "Please wait before requesting another verification email".to_string(),
+        ));
+    }
+
+    send_verification(
+        &pool,
+        &config,
+        sender.as_ref(),
+        auth_user.user_id,
+        &user.email,
+    )
+    .await?;
+
+    Ok(Json(()))
+}
+
 pub async fn get_user(
+    auth_user: AuthUser,
     State(pool): State<SqlitePool>,
     Path(id): Path<i64>,
-) -> Result<Json<User>, StatusCode> {
+) -> Result<Json<User>, ApiError> {
+    require_self_or(&pool, &auth_user, id, Permission::UserRead).await?;
+
     let user = sqlx::query_as!(
         User,
         r#"
         SELECT id, email, password_hash, full_name, phone, 
                role as "role: UserRole", verified, profile_image_url, 
-               created_at, updated_at,
-               reset_token as "reset_token?",
-               datetime(reset_token_expires) as "reset_token_expires?: String"
+               created_at, updated_at
         FROM users
         WHERE id = ?
         "#,
@@ -56,8 +216,8 @@ pub async fn get_user(
     )
     .fetch_optional(&pool)
     .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-    .ok_or(StatusCode::NOT_FOUND)?;
+    .map_err(ApiError::DatabaseError)?
+    .ok_or(ApiError::NotFound)?;
 
     Ok(Json(user))
 }
@@ -65,15 +225,13 @@ pub async fn get_user(
 pub async fn get_users_by_role(
     State(pool): State<SqlitePool>,
     Path(role): Path<UserRole>,
-) -> Result<Json<Vec<User>>, StatusCode> {
+) -> Result<Json<Vec<User>>, ApiError> {
     let users = sqlx::query_as!(
         User,
         r#"
         SELECT id, email, password_hash, full_name, phone, 
                role as "role: UserRole", verified, profile_image_url, 
-               created_at, updated_at,
-               reset_token as "reset_token?",
-               reset_token_expires as "reset_token_expires?: String"
+               created_at, updated_at
         FROM users
         WHERE role = ?
         "#,
@@ -81,7 +239,7 @@ pub async fn get_users_by_role(
     )
     .fetch_all(&pool)
     .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .map_err(ApiError::DatabaseError)?;
 
     Ok(Json(users))
 }
@@ -100,9 +258,7 @@ pub async fn list_users(State(pool): State<SqlitePool>) -> Result<Json<Vec<User>
             verified,
             profile_image_url as "profile_image_url?",
             datetime(created_at) as "created_at?: String",
-            datetime(updated_at) as "updated_at?: String",
-            reset_token as "reset_token?",
-            datetime(reset_token_expires) as "reset_token_expires?: String"
+            datetime(updated_at) as "updated_at?: String"
         FROM users
         ORDER BY created_at DESC
         "#,
@@ -114,68 +270,253 @@ pub async fn list_users(State(pool): State<SqlitePool>) -> Result<Json<Vec<User>
     Ok(Json(users))
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn login(
     State(pool): State<SqlitePool>,
+    State(keys): State<Arc<KeyRing>>,
+    State(config): State<Arc<Config>>,
+    State(attempts): State<Arc<dyn AttemptStore>>,
+    State(mail): State<MailQueue>,
+    mode: AuthMode,
+    client: ClientInfo,
     Json(credentials): Json<LoginCredentials>,
-) -> Result<Json<AuthResponse>, ApiError> {
+) -> Result<(CookieJar, Json<LoginResponse>), ApiError> {
+    let ip = client.ip_address.as_deref();
+    let email = credentials.email.trim().to_lowercase();
+    login_attempts::check(attempts.as_ref(), &email, ip).await?;
+
     let user = sqlx::query_as!(
         User,
         r#"
         SELECT id, email, password_hash, full_name, phone, 
                role as "role: UserRole", verified, profile_image_url, 
-               created_at, updated_at, reset_token as "reset_token?",
-            reset_token_expires as "reset_token_expires?: String"
+               created_at, updated_at
         FROM users
-        WHERE email = ?
+        WHERE lower(email) = ?
         "#,
-        credentials.email
+        email
     )
     .fetch_optional(&pool)
     .await
-    .map_err(ApiError::DatabaseError)?
-    .ok_or(ApiError::AuthenticationError(
-        "Invalid credentials".to_string(),
-    ))?;
+    .map_err(ApiError::DatabaseError)?;
+
+    let verification = match &user {
+        Some(user) => Some(password::verify(&credentials.password, &user.password_hash).await?),
+        None => {
+            password::verify_dummy(&credentials.password).await?;
+            None
+        }
+    };
+
+    // Unknown addresses count as failures too so they can't be told apart
+    let user = match (user, verification) {
+        (Some(user), Some(verification)) if verification.valid => {
+            if verification.needs_rehash {
+                rehash_password(&pool, &user, &credentials.password).await;
+            }
+            user
+        }
+        (user, _) => {
+            let locked =
+                login_attempts::record_failure(attempts.as_ref(), &config, &email, ip).await?;
+            let mut event = AuditEvent::new("auth.login_failed")
+                .after(json!({ "email": email, "locked": locked }));
+            if let Some(user_id) = user.as_ref().and_then(|user| user.id) {
+                event = event.target("user", user_id);
+            }
+            event.record(&pool, &client).await?;
+            if let (true, Some(user)) = (locked, user) {
+                login_attempts::notify_locked(&mail, &config, &user.email).await?;
+            }
+            return Err(ApiError::AuthenticationError(
+                "Invalid credentials".to_string(),
+            ));
+        }
+    };
 
-    if !verify(credentials.password.as_bytes(), &user.password_hash)
-        .map_err(|_| ApiError::AuthenticationError("Invalid credentials".to_string()))?
+    // The password was accepted, a second factor may still be required
+    let mut response =
+        finish_login(&pool, &keys, user, &client, json!({ "method": "password" })).await?;
+    // Failures keep counting until the second factor is passed too
+    if let LoginResponse::Authenticated(_) = response {
+        login_attempts::clear_account(attempts.as_ref(), &email).await?;
+    }
+    let jar = mode.deliver_login(&config, &mut response);
+
+    Ok((jar, Json(response)))
+}
+
+// Moves the stored hash to the current algorithm and parameters. The login
+// goes ahead even if this fails, the next one tries again.
+async fn rehash_password(pool: &SqlitePool, user: &User, password: &str) {
+    let password_hash = match password::hash(password).await {
+        Ok(password_hash) => password_hash,
+        Err(err) => {
+            tracing::warn!(
+                "could not rehash password for user {:?}: {:?}",
+                user.id,
+                err
+            );
+            return;
+        }
+    };
+
+    // Only replace the hash we verified against, a concurrent reset wins
+    if let Err(err) = sqlx::query!(
+        "UPDATE users SET password_hash = ? WHERE id = ? AND password_hash = ?",
+        password_hash,
+        user.id,
+        user.password_hash
+    )
+    .execute(pool)
+    .await
     {
-        return Err(ApiError::AuthenticationError(
-            "Invalid credentials".to_string(),
-        ));
+        tracing::warn!(
+            "could not store rehashed password for user {:?}: {}",
+            user.id,
+            err
+        );
     }
+}
 
-    let token = create_token(&user)?;
-    let refresh_token = create_refresh_token(&user)?;
+// Lifts a lockout before it expires
+pub async fn unlock_user(
+    auth_user: AuthUser,
+    State(pool): State<SqlitePool>,
+    State(attempts): State<Arc<dyn AttemptStore>>,
+    client: ClientInfo,
+    Path(id): Path<i64>,
+) -> Result<Json<()>, ApiError> {
+    let email = sqlx::query_scalar!("SELECT email FROM users WHERE id = ?", id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(ApiError::DatabaseError)?
+        .ok_or(ApiError::NotFound)?;
 
-    Ok(Json(AuthResponse {
-        token,
-        refresh_token,
-        user,
-    }))
+    login_attempts::clear_account(attempts.as_ref(), &email).await?;
+
+    AuditEvent::new("user.unlock")
+        .by(&auth_user)
+        .target("user", id)
+        .record(&pool, &client)
+        .await?;
+
+    Ok(Json(()))
 }
 
-// Example of a protected route
-pub async fn get_profile(
+pub async fn update_user_role(
     auth_user: AuthUser,
     State(pool): State<SqlitePool>,
+    client: ClientInfo,
+    Path(id): Path<i64>,
+    Json(update): Json<RoleUpdate>,
 ) -> Result<Json<User>, ApiError> {
+    let mut tx = pool.begin().await.map_err(ApiError::DatabaseError)?;
+
+    let previous = sqlx::query_scalar!(
+        r#"SELECT role as "role: UserRole" FROM users WHERE id = ?"#,
+        id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .ok_or(ApiError::NotFound)?;
+
     let user = sqlx::query_as!(
         User,
         r#"
-        SELECT id, email, password_hash, full_name, phone, 
-               role as "role: UserRole", verified, profile_image_url, 
-               created_at, updated_at, reset_token as "reset_token?",
-            reset_token_expires as "reset_token_expires?: String"
+        UPDATE users
+        SET role = ?, updated_at = CURRENT_TIMESTAMP
+        WHERE id = ?
+        RETURNING id, email, password_hash, full_name, phone, role as "role: UserRole",
+                  verified, profile_image_url, created_at, updated_at
+        "#,
+        update.role,
+        id
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    // Tokens carry the role, so the user signs in again to pick up the new
+    // one and any MFA policy that comes with it
+    let sessions_revoked = user.role != previous;
+    if sessions_revoked {
+        revoke_all_sessions(&mut *tx, id).await?;
+    }
+
+    AuditEvent::new("user.update_role")
+        .by(&auth_user)
+        .target("user", id)
+        .before(json!({ "role": previous }))
+        .after(json!({ "role": user.role, "sessions_revoked": sessions_revoked }))
+        .record(&mut *tx, &client)
+        .await?;
+
+    tx.commit().await.map_err(ApiError::DatabaseError)?;
+
+    Ok(Json(user))
+}
+
+// Lets support staff see the app as the user. Admins can't be impersonated,
+// so this never hands out more rights than the caller already has.
+pub async fn impersonate_user(
+    auth_user: AuthUser,
+    State(pool): State<SqlitePool>,
+    State(keys): State<Arc<KeyRing>>,
+    State(config): State<Arc<Config>>,
+    client: ClientInfo,
+    Path(id): Path<i64>,
+) -> Result<Json<ImpersonationResponse>, ApiError> {
+    let session_id = auth_user.require_session()?;
+    if id == auth_user.user_id {
+        return Err(ApiError::ValidationError(
+            "You can't impersonate yourself".to_string(),
+        ));
+    }
+
+    let user = sqlx::query_as!(
+        User,
+        r#"
+        SELECT id, email, password_hash, full_name, phone,
+               role as "role: UserRole", verified, profile_image_url,
+               created_at, updated_at
         FROM users
         WHERE id = ?
         "#,
-        auth_user.user_id
+        id
     )
     .fetch_optional(&pool)
     .await
     .map_err(ApiError::DatabaseError)?
     .ok_or(ApiError::NotFound)?;
 
-    Ok(Json(user))
+    if user.role == UserRole::Admin {
+        return Err(ApiError::AuthorizationError(
+            "Admins can't be impersonated".to_string(),
+        ));
+    }
+
+    tracing::warn!(
+        target: "audit",
+        actor_id = auth_user.user_id,
+        user_id = id,
+        "impersonation started"
+    );
+    AuditEvent::new("user.impersonate")
+        .by(&auth_user)
+        .target("user", id)
+        .after(json!({ "minutes": config.impersonation_token_minutes }))
+        .record(&pool, &client)
+        .await?;
+
+    let response = create_impersonation_token(
+        &keys,
+        user,
+        auth_user.user_id,
+        session_id,
+        config.impersonation_token_minutes,
+    )?;
+
+    Ok(Json(response))
 }
@@ -0,0 +1,260 @@
+// JWT signing and verification keys loaded from the environment
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Context};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    errors::ErrorKind,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use rsa::{
+    pkcs1::DecodeRsaPublicKey,
+    pkcs8::{
+        spki::{ObjectIdentifier, SubjectPublicKeyInfoRef},
+        DecodePublicKey,
+    },
+    traits::PublicKeyParts,
+    RsaPublicKey,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+const PLACEHOLDER_SECRET: &str = "your-secret-key";
+const MIN_SECRET_LEN: usize = 32;
+const ED25519_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+
+/// Holds the key used to sign new tokens plus every key that is still
+/// accepted for verification, indexed by `kid`.
+///
+/// Rotating a key means adding a new one, switching `JWT_SIGNING_KID` to
+/// it and keeping the old one around until the last token it signed has
+/// expired.
+pub struct KeyRing {
+    signing_kid: String,
+    encoding_key: EncodingKey,
+    decoding_keys: HashMap<String, DecodingKey>,
+    validation: Validation,
+    jwks: JwkSet,
+}
+
+impl KeyRing {
+    /// `JWT_ALGORITHM` selects the signing scheme (`HS256` by default).
+    ///
+    /// * `HS256` reads `JWT_KEYS` as `kid:secret` pairs separated by commas.
+    /// * `RS256` and `EdDSA` read the private key from the PEM file at
+    ///   `JWT_PRIVATE_KEY` and `JWT_PUBLIC_KEYS` as `kid:path` pairs.
+    ///
+    /// The optional `JWT_SIGNING_KID` defaults to the first key.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let algorithm = match std::env::var("JWT_ALGORITHM").as_deref() {
+            Err(_) | Ok("HS256") => Algorithm::HS256,
+            Ok("RS256") => Algorithm::RS256,
+            Ok("EdDSA") => Algorithm::EdDSA,
+            Ok(other) => bail!("Unsupported JWT_ALGORITHM `{other}`"),
+        };
+        let signing_kid = std::env::var("JWT_SIGNING_KID").ok();
+
+        if algorithm == Algorithm::HS256 {
+            let keys = std::env::var("JWT_KEYS").context("JWT_KEYS must be set")?;
+            Self::from_secrets(&keys, signing_kid.as_deref())
+        } else {
+            let private_key =
+                std::env::var("JWT_PRIVATE_KEY").context("JWT_PRIVATE_KEY must be set")?;
+            let public_keys =
+                std::env::var("JWT_PUBLIC_KEYS").context("JWT_PUBLIC_KEYS must be set")?;
+            Self::from_pem_files(
+                algorithm,
+                &private_key,
+                &public_keys,
+                signing_kid.as_deref(),
+            )
+        }
+    }
+
+    fn from_secrets(keys: &str, signing_kid: Option<&str>) -> anyhow::Result<Self> {
+        let secrets = parse_pairs("JWT_KEYS", keys)?;
+        for (kid, secret) in &secrets {
+            if *secret == PLACEHOLDER_SECRET {
+                bail!("JWT key `{kid}` still uses the placeholder secret");
+            }
+            if secret.len() < MIN_SECRET_LEN {
+                bail!("JWT key `{kid}` must be at least {MIN_SECRET_LEN} bytes long");
+            }
+        }
+
+        let signing_kid = pick_signing_kid("JWT_KEYS", &secrets, signing_kid)?;
+        let (_, signing_secret) = secrets.iter().find(|(kid, _)| *kid == signing_kid).unwrap();
+
+        Ok(KeyRing {
+            signing_kid: signing_kid.to_string(),
+            encoding_key: EncodingKey::from_secret(signing_secret.as_bytes()),
+            decoding_keys: secrets
+                .iter()
+                .map(|(kid, secret)| (kid.to_string(), DecodingKey::from_secret(secret.as_bytes())))
+                .collect(),
+            validation: Validation::new(Algorithm::HS256),
+            // Shared secrets are never published
+            jwks: JwkSet { keys: Vec::new() },
+        })
+    }
+
+    fn from_pem_files(
+        algorithm: Algorithm,
+        private_key: &str,
+        public_keys: &str,
+        signing_kid: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let paths = parse_pairs("JWT_PUBLIC_KEYS", public_keys)?;
+        let signing_kid = pick_signing_kid("JWT_PUBLIC_KEYS", &paths, signing_kid)?;
+
+        let private_pem = std::fs::read(private_key)
+            .with_context(|| format!("Failed to read JWT private key `{private_key}`"))?;
+        let encoding_key = match algorithm {
+            Algorithm::RS256 => EncodingKey::from_rsa_pem(&private_pem),
+            _ => EncodingKey::from_ed_pem(&private_pem),
+        }
+        .context("Invalid JWT private key")?;
+
+        let mut decoding_keys = HashMap::new();
+        let mut jwks = Vec::new();
+        for (kid, path) in paths {
+            let pem = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read JWT public key `{path}`"))?;
+            let (decoding_key, params) = match algorithm {
+                Algorithm::RS256 => (DecodingKey::from_rsa_pem(pem.as_bytes()), rsa_jwk(&pem)),
+                _ => (DecodingKey::from_ed_pem(pem.as_bytes()), ed25519_jwk(&pem)),
+            };
+            let decoding_key =
+                decoding_key.with_context(|| format!("Invalid JWT public key `{kid}`"))?;
+            let params = params.with_context(|| format!("Invalid JWT public key `{kid}`"))?;
+
+            decoding_keys.insert(kid.to_string(), decoding_key);
+            jwks.push(Jwk {
+                common: CommonParameters {
+                    public_key_use: Some(PublicKeyUse::Signature),
+                    key_algorithm: Some(match algorithm {
+                        Algorithm::RS256 => KeyAlgorithm::RS256,
+                        _ => KeyAlgorithm::EdDSA,
+                    }),
+                    key_id: Some(kid.to_string()),
+                    ..CommonParameters::default()
+                },
+                algorithm: params,
+            });
+        }
+
+        let keyring = KeyRing {
+            signing_kid: signing_kid.to_string(),
+            encoding_key,
+            decoding_keys,
+            validation: Validation::new(algorithm),
+            jwks: JwkSet { keys: jwks },
+        };
+
+        // Make sure the private key matches the public key published for its kid
+        #[derive(Serialize, Deserialize)]
+        struct Probe {
+            exp: i64,
+        }
+        let probe = keyring.encode(&Probe { exp: i64::MAX })?;
+        keyring.decode::<Probe>(&probe).with_context(|| {
+            format!("JWT_PRIVATE_KEY does not match the public key for `{signing_kid}`")
+        })?;
+
+        Ok(keyring)
+    }
+
+    /// Signs `claims` with the active key and tags the header with its `kid`.
+    pub fn encode<T: Serialize>(&self, claims: &T) -> jsonwebtoken::errors::Result<String> {
+        let header = Header {
+            kid: Some(self.signing_kid.clone()),
+            ..Header::new(self.validation.algorithms[0])
+        };
+        encode(&header, claims, &self.encoding_key)
+    }
+
+    /// Verifies a token against the key named by its `kid` header.
+    /// Tokens without a `kid` or with an unknown one are rejected.
+    pub fn decode<T: DeserializeOwned>(&self, token: &str) -> jsonwebtoken::errors::Result<T> {
+        let header = decode_header(token)?;
+        let key = header
+            .kid
+            .and_then(|kid| self.decoding_keys.get(&kid))
+            .ok_or(ErrorKind::InvalidToken)?;
+        decode::<T>(token, key, &self.validation).map(|data| data.claims)
+    }
+
+    /// Public verification keys, empty when tokens are signed with HS256.
+    pub fn jwks(&self) -> &JwkSet {
+        &self.jwks
+    }
+}
+
+// Splits `kid:value` pairs separated by commas
+fn parse_pairs<'a>(var: &str, value: &'a str) -> anyhow::Result<Vec<(&'a str, &'a str)>> {
+    let mut pairs: Vec<(&str, &str)> = Vec::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (kid, value) = entry
+            .split_once(':')
+            .ok_or_else(|| anyhow!("{var} entries must look like `kid:value`"))?;
+        let kid = kid.trim();
+        if kid.is_empty() {
+            bail!("{var} contains an entry without a kid");
+        }
+        if pairs.iter().any(|(k, _)| *k == kid) {
+            bail!("JWT key `{kid}` is defined more than once");
+        }
+        pairs.push((kid, value.trim()));
+    }
+    Ok(pairs)
+}
+
+fn pick_signing_kid<'a>(
+    var: &str,
+    pairs: &[(&'a str, &str)],
+    signing_kid: Option<&'a str>,
+) -> anyhow::Result<&'a str> {
+    let kid = match signing_kid {
+        Some(kid) => kid,
+        None => pairs
+            .first()
+            .map(|(kid, _)| *kid)
+            .ok_or_else(|| anyhow!("{var} does not contain any key"))?,
+    };
+    if !pairs.iter().any(|(k, _)| *k == kid) {
+        bail!("JWT_SIGNING_KID `{kid}` is not in {var}");
+    }
+    Ok(kid)
+}
+
+fn rsa_jwk(pem: &str) -> anyhow::Result<AlgorithmParameters> {
+    let key =
+        RsaPublicKey::from_public_key_pem(pem).or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))?;
+    Ok(AlgorithmParameters::RSA(RSAKeyParameters {
+        key_type: RSAKeyType::RSA,
+        n: URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+        e: URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+    }))
+}
+
+fn ed25519_jwk(pem: &str) -> anyhow::Result<AlgorithmParameters> {
+    let pem = pem::parse(pem)?;
+    let spki = SubjectPublicKeyInfoRef::try_from(pem.contents()).map_err(|err| anyhow!("{err}"))?;
+    if spki.algorithm.oid != ED25519_OID {
+        bail!("not an Ed25519 public key");
+    }
+    let x = spki
+        .subject_public_key
+        .as_bytes()
+        .ok_or_else(|| anyhow!("malformed Ed25519 public key"))?;
+    Ok(AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+        key_type: OctetKeyPairType::OctetKeyPair,
+        curve: EllipticCurve::Ed25519,
+        x: URL_SAFE_NO_PAD.encode(x),
+    }))
+}
@@ -0,0 +1,418 @@
+// Failed login tracking with exponential backoff and temporary lockout
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::bail;
+use axum::async_trait;
+use sqlx::SqlitePool;
+use time::OffsetDateTime;
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::mail::{MailQueue, Template};
+
+// Failures older than this no longer count
+const ATTEMPT_WINDOW: i64 = 60 * 60;
+const MAX_BACKOFF: i64 = 5 * 60;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Attempts {
+    pub failures: i64,
+    pub last_failure_at: i64,
+    pub blocked_until: Option<i64>,
+}
+
+// Where failure counts live. Keys look like "account:<email>" or
+// "ip:<address>", times are unix seconds.
+#[async_trait]
+pub trait AttemptStore: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<Attempts>, sqlx::Error>;
+    // Counts a failure and returns the new total, starting over when the
+    // previous failure happened before `reset_before`
+    async fn record_failure(
+        &self,
+        key: &str,
+        now: i64,
+        reset_before: i64,
+    ) -> Result<i64, sqlx::Error>;
+    async fn block(&self, key: &str, until: i64) -> Result<(), sqlx::Error>;
+    async fn clear(&self, key: &str) -> Result<(), sqlx::Error>;
+}
+
+pub struct SqliteAttemptStore {
+    pool: SqlitePool,
+}
+
+impl SqliteAttemptStore {
+    pub fn new(pool: SqlitePool) -> Self {
+        SqliteAttemptStore { pool }
+    }
+}
+
+#[async_trait]
+impl AttemptStore for SqliteAttemptStore {
+    async fn get(&self, key: &str) -> Result<Option<Attempts>, sqlx::Error> {
+        sqlx::query_as!(
+            Attempts,
+            r#"
+            SELECT failures, last_failure_at, blocked_until
+            FROM login_attempts
+            WHERE key = ?
+            "#,
+            key
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn record_failure(
+        &self,
+        key: &str,
+        now: i64,
+        reset_before: i64,
+    ) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO login_attempts (key, failures, last_failure_at)
+            VALUES (?, 1, ?)
+            ON CONFLICT (key) DO UPDATE
+            SET failures = CASE WHEN last_failure_at < ? THEN 1 ELSE failures + 1 END,
+                last_failure_at = excluded.last_failure_at
+            RETURNING failures
+            "#,
+            key,
+            now,
+            reset_before
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn block(&self, key: &str, until: i64) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE login_attempts SET blocked_until = ? WHERE key = ?",
+            until,
+            key
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn clear(&self, key: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!("DELETE FROM login_attempts WHERE key = ?", key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+// Keeps counts in process memory. Counts are lost on restart and not
+// shared between instances, so this is meant for tests and local runs.
+#[derive(Default)]
+pub struct MemoryAttemptStore {
+    attempts: Mutex<HashMap<String, Attempts>>,
+}
+
+#[async_trait]
+impl AttemptStore for MemoryAttemptStore {
+    async fn get(&self, key: &str) -> Result<Option<Attempts>, sqlx::Error> {
+        Ok(self.attempts.lock().unwrap().get(key).copied())
+    }
+
+    async fn record_failure(
+        &self,
+        key: &str,
+        now: i64,
+        reset_before: i64,
+    ) -> Result<i64, sqlx::Error> {
+        let mut attempts = self.attempts.lock().unwrap();
+        let entry = attempts.entry(key.to_string()).or_insert(Attempts {
+            failures: 0,
+            last_failure_at: now,
+            blocked_until: None,
+        });
+        if entry.last_failure_at < reset_before {
+            entry.failures = 0;
+        }
+        entry.failures += 1;
+        entry.last_failure_at = now;
+        Ok(entry.failures)
+    }
+
+    async fn block(&self, key: &str, until: i64) -> Result<(), sqlx::Error> {
+        if let Some(entry) = self.attempts.lock().unwrap().get_mut(key) {
+            entry.blocked_until = Some(until);
+        }
+        Ok(())
+    }
+
+    async fn clear(&self, key: &str) -> Result<(), sqlx::Error> {
+        self.attempts.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+// LOGIN_ATTEMPT_STORE picks the store: sqlite (default) or memory
+pub fn attempt_store_from_env(pool: &SqlitePool) -> anyhow::Result<Arc<dyn AttemptStore>> {
+    let store: Arc<dyn AttemptStore> = match std::env::var("LOGIN_ATTEMPT_STORE").as_deref() {
+        Err(_) | Ok("sqlite") => Arc::new(SqliteAttemptStore::new(pool.clone())),
+        Ok("memory") => Arc::new(MemoryAttemptStore::default()),
+        Ok(other) => bail!("Unsupported LOGIN_ATTEMPT_STORE `{other}`"),
+    };
+    Ok(store)
+}
+
+fn account_key(email: &str) -> String {
+    format!("account:{}", email.to_lowercase())
+}
+
+fn ip_key(ip: &str) -> String {
+    format!("ip:{ip}")
+}
+
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+// The first half of the allowed failures are free, then the wait doubles
+// with every failure until the key is locked out
+fn delay(failures: i64, max_failures: i64, lockout: i64) -> i64 {
+    if failures >= max_failures {
+        return lockout;
+    }
+    let free = max_failures / 2;
+    if failures <= free {
+        return 0;
+    }
+    (1i64 << (failures - free - 1).min(16)).min(MAX_BACKOFF)
+}
+
+// Rejects the login while the account or the client IP is blocked
+pub async fn check(
+    store: &dyn AttemptStore,
+    email: &str,
+    ip: Option<&str>,
+) -> Result<(), ApiError> {
+    let now = now();
+    let keys = std::iter::once(account_key(email)).chain(ip.map(ip_key));
+
+    for key in keys {
+        let blocked_until = store
+            .get(&key)
+            .await
+            .map_err(ApiError::DatabaseError)?
+            .and_then(|attempts| attempts.blocked_until)
+            .filter(|until| *until > now);
+
+        if let Some(until) = blocked_until {
+            return Err(ApiError::TooManyRequests(format!(
+                "Too many failed login attempts, try again in {} seconds",
+                until - now
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+async fn count_failure(
+    store: &dyn AttemptStore,
+    key: &str,
+    max_failures: i64,
+    lockout: i64,
+) -> Result<i64, ApiError> {
+    let now = now();
+    let failures = store
+        .record_failure(key, now, now - ATTEMPT_WINDOW)
+        .await
+        .map_err(ApiError::DatabaseError)?;
+
+    let wait = delay(failures, max_failures, lockout);
+    if wait > 0 {
+        store
+            .block(key, now + wait)
+            .await
+            .map_err(ApiError::DatabaseError)?;
+    }
+
+    Ok(failures)
+}
+
+// Returns true when this failure locked the account, so the owner is told
+// about it only once
+pub async fn record_failure(
+    store: &dyn AttemptStore,
+    config: &Config,
+    email: &str,
+    ip: Option<&str>,
+) -> Result<bool, ApiError> {
+    let failures = count_failure(
+        store,
+        &account_key(email),
+        config.login_max_failures,
+        config.login_lockout_seconds,
+    )
+    .await?;
+
+    if let Some(ip) = ip {
+        count_failure(
+            store,
+            &ip_key(ip),
+            config.login_max_failures_per_ip,
+            config.login_lockout_seconds,
+        )
+        .await?;
+    }
+
+    Ok(failures == config.login_max_failures)
+}
+
+// Called after a successful login and by admins to lift a lockout. The IP
+// counter is left alone so one valid account can't reset it.
+pub async fn clear_account(store: &dyn AttemptStore, email: &str) -> Result<(), ApiError> {
+    store
+        .clear(&account_key(email))
+        .await
+        .map_err(ApiError::DatabaseError)
+}
+
+// Tells the owner once their account gets locked
+pub async fn notify_locked(mail: &MailQueue, config: &Config, email: &str) -> Result<(), ApiError> {
+    tracing::warn!("account {} locked after failed logins", email);
+    mail.enqueue(
+        email,
+        Template::Notification {
+            subject: "Your account has been temporarily locked".to_string(),
+            message: format!(
+                "There were {} failed sign-in attempts on your yRealEstate \
+                 account, so it is locked for {} minutes. If this was not you, \
+                 please change your password once the lock expires.",
+                config.login_max_failures,
+                config.login_lockout_seconds / 60
+            ),
+        },
+    )
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_store_counts_failures_within_the_window() {
+        let store = MemoryAttemptStore::default();
+        assert!(store.get("account:a@x.io").await.unwrap().is_none());
+
+        assert_eq!(
+            store
+                .record_failure("account:a@x.io", 100, 0)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            store
+                .record_failure("account:a@x.io", 110, 0)
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            store.record_failure("ip:10.0.0.1", 110, 0).await.unwrap(),
+            1
+        );
+
+        let attempts = store.get("account:a@x.io").await.unwrap().unwrap();
+        assert_eq!(attempts.failures, 2);
+        assert_eq!(attempts.last_failure_at, 110);
+        assert_eq!(attempts.blocked_until, None);
+    }
+
+    #[tokio::test]
+    async fn memory_store_starts_over_after_the_window() {
+        let store = MemoryAttemptStore::default();
+        store
+            .record_failure("account:a@x.io", 100, 0)
+            .await
+            .unwrap();
+        store
+            .record_failure("account:a@x.io", 200, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            store
+                .record_failure("account:a@x.io", 5000, 1000)
+                .await
+                .unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_store_blocks_and_clears() {
+        let store = MemoryAttemptStore::default();
+        // Nothing to block before the first failure
+        store.block("account:a@x.io", 500).await.unwrap();
+        assert!(store.get("account:a@x.io").await.unwrap().is_none());
+
+        store
+            .record_failure("account:a@x.io", 100, 0)
+            .await
+            .unwrap();
+        store.block("account:a@x.io", 500).await.unwrap();
+        assert_eq!(
+            store
+                .get("account:a@x.io")
+                .await
+                .unwrap()
+                .unwrap()
+                .blocked_until,
+            Some(500)
+        );
+
+        store.clear("account:a@x.io").await.unwrap();
+        assert!(store.get("account:a@x.io").await.unwrap().is_none());
+    }
+
+    #[test]
+    fn delay_doubles_after_the_free_failures_then_locks() {
+        let delays: Vec<i64> = (1..=10).map(|failures| delay(failures, 10, 900)).collect();
+        assert_eq!(delays, [0, 0, 0, 0, 0, 1, 2, 4, 8, 900]);
+        assert_eq!(delay(90, 100, 900), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn check_rejects_a_blocked_account_or_ip() {
+        let store = MemoryAttemptStore::default();
+        assert!(check(&store, "A@x.io", Some("10.0.0.1")).await.is_ok());
+
+        for _ in 0..3 {
+            count_failure(&store, &ip_key("10.0.0.1"), 3, 60)
+                .await
+                .unwrap();
+        }
+        assert!(check(&store, "a@x.io", None).await.is_ok());
+        assert!(matches!(
+            check(&store, "a@x.io", Some("10.0.0.1")).await,
+            Err(ApiError::TooManyRequests(_))
+        ));
+
+        for _ in 0..3 {
+            count_failure(&store, &account_key("a@x.io"), 3, 60)
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            check(&store, "A@X.io", None).await,
+            Err(ApiError::TooManyRequests(_))
+        ));
+        clear_account(&store, "a@x.io").await.unwrap();
+        assert!(check(&store, "a@x.io", None).await.is_ok());
+    }
+}
@@ -0,0 +1,38 @@
+use std::path::PathBuf;
+
+use axum::async_trait;
+use lettre::message::Mailbox;
+use uuid::Uuid;
+
+use super::{build_message, Mailer, OutgoingEmail};
+
+// Writes every message as an .eml file into a directory
+pub struct FileMailer {
+    dir: PathBuf,
+    from: Mailbox,
+}
+
+impl FileMailer {
+    pub fn new(dir: impl Into<PathBuf>, from: Mailbox) -> Self {
+        FileMailer {
+            dir: dir.into(),
+            from,
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: &OutgoingEmail) -> anyhow::Result<()> {
+        let message = build_message(&self.from, email)?;
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        let name = format!(
+            "{}-{}.eml",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S"),
+            Uuid::new_v4()
+        );
+        tokio::fs::write(self.dir.join(name), message.formatted()).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+// Outbound email: templates, a persistent queue and pluggable delivery backends
+
+mod file;
+mod outbox;
+mod queue;
+mod smtp;
+mod templates;
+
+pub use file::FileMailer;
+pub use outbox::OutboxMailer;
+pub use queue::MailQueue;
+pub use smtp::SmtpMailer;
+pub use templates::Template;
+
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use axum::async_trait;
+use lettre::{message::header::ContentType, message::Mailbox, Message};
+use sqlx::SqlitePool;
+
+#[derive(Debug, Clone)]
+pub struct OutgoingEmail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// A delivery backend. Handlers never call it directly, they go through the
+// MailQueue so failed sends are retried.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &OutgoingEmail) -> anyhow::Result<()>;
+}
+
+// Writes mail to the log instead of sending it
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: &OutgoingEmail) -> anyhow::Result<()> {
+        tracing::info!("mail to {}: {}\n{}", email.to, email.subject, email.body);
+        Ok(())
+    }
+}
+
+// MAIL_BACKEND picks the backend: smtp, file, outbox (default) or log
+pub fn mailer_from_env(pool: &SqlitePool) -> anyhow::Result<Arc<dyn Mailer>> {
+    let from: Mailbox = std::env::var("MAIL_FROM")
+        .unwrap_or_else(|_| "yRealEstate <no-reply@localhost>".to_string())
+        .parse()
+        .context("MAIL_FROM is not a valid address")?;
+
+    let mailer: Arc<dyn Mailer> = match std::env::var("MAIL_BACKEND").as_deref() {
+        Ok("smtp") => Arc::new(SmtpMailer::from_env(from)?),
+        Ok("file") => Arc::new(FileMailer::new(
+            std::env::var("MAIL_DIR").unwrap_or_else(|_| "mail".to_string()),
+            from,
+        )),
+        Err(_) | Ok("outbox") => Arc::new(OutboxMailer::new(pool.clone())),
+        Ok("log") => Arc::new(LogMailer),
+        Ok(other) => bail!("Unsupported MAIL_BACKEND `{other}`"),
+    };
+    Ok(mailer)
+}
+
+fn build_message(from: &Mailbox, email: &OutgoingEmail) -> anyhow::Result<Message> {
+    Ok(Message::builder()
+        .from(from.clone())
+        .to(email.to.parse().context("invalid recipient address")?)
+        .subject(email.subject.as_str())
+        .header(ContentType::TEXT_PLAIN)
+        .body(email.body.clone())?)
+}
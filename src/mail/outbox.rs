@@ -0,0 +1,33 @@
+use axum::async_trait;
+use sqlx::SqlitePool;
+
+use super::{Mailer, OutgoingEmail};
+
+// Stores every message in the mail_outbox table instead of sending it
+pub struct OutboxMailer {
+    pool: SqlitePool,
+}
+
+impl OutboxMailer {
+    pub fn new(pool: SqlitePool) -> Self {
+        OutboxMailer { pool }
+    }
+}
+
+#[async_trait]
+impl Mailer for OutboxMailer {
+    async fn send(&self, email: &OutgoingEmail) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO mail_outbox (recipient, subject, body)
+            VALUES (?, ?, ?)
+            "#,
+            email.to,
+            email.subject,
+            email.body
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use std::{sync::Arc, time::Duration};
+
+use sqlx::SqlitePool;
+use tokio::sync::Notify;
+
+use super::{Mailer, OutgoingEmail, Template};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+const BATCH_SIZE: i64 = 20;
+const MAX_ATTEMPTS: i64 = 8;
+const RETRY_BASE_SECONDS: i64 = 30;
+
+// Persistent outgoing mail queue. Handlers enqueue, a background worker
+// delivers and retries with exponential backoff.
+#[derive(Clone)]
+pub struct MailQueue {
+    pool: SqlitePool,
+    wake: Arc<Notify>,
+}
+
+impl MailQueue {
+    pub fn new(pool: SqlitePool) -> Self {
+        MailQueue {
+            pool,
+            wake: Arc::new(Notify::new()),
+        }
+    }
+
+    pub async fn enqueue(&self, to: &str, template: Template) -> Result<(), sqlx::Error> {
+        let (subject, body) = template.render();
+
+        sqlx::query!(
+            r#"
+            INSERT INTO mail_queue (recipient, subject, body)
+            VALUES (?, ?, ?)
+            "#,
+            to,
+            subject,
+            body
+        )
+        .execute(&self.pool)
+        .await?;
+
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    pub fn spawn_worker(&self, mailer: Arc<dyn Mailer>) {
+        let queue = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(err) = queue.deliver_due(mailer.as_ref()).await {
+                    tracing::error!("mail queue: {}", err);
+                }
+                tokio::select! {
+                    _ = queue.wake.notified() => {}
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                }
+            }
+        });
+    }
+
+    async fn deliver_due(&self, mailer: &dyn Mailer) -> Result<(), sqlx::Error> {
+        let due = sqlx::query!(
+            r#"
+            SELECT id as "id!", recipient, subject, body, attempts
+            FROM mail_queue
+            WHERE status = 'pending' AND next_attempt_at <= CURRENT_TIMESTAMP
+            ORDER BY id
+            LIMIT ?
+            "#,
+            BATCH_SIZE
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        for row in due {
+            let email = OutgoingEmail {
+                to: row.recipient,
+                subject: row.subject,
+                body: row.body,
+            };
+            let attempts = row.attempts + 1;
+
+            match mailer.send(&email).await {
+                Ok(()) => {
+                    sqlx::query!(
+                        r#"
+                        UPDATE mail_queue
+                        SET status = 'sent', attempts = ?, sent_at = CURRENT_TIMESTAMP,
+                            last_error = NULL
+                        WHERE id = ?
+                        "#,
+                        attempts,
+                        row.id
+                    )
+                    .execute(&self.pool)
+                    .await?;
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        "mail {} to {} failed (attempt {}): {}",
+                        row.id,
+                        email.to,
+                        attempts,
+                        err
+                    );
+                    let status = if attempts >= MAX_ATTEMPTS {
+                        "failed"
+                    } else {
+                        "pending"
+                    };
+                    let delay =
+                        format!("+{} seconds", RETRY_BASE_SECONDS << (attempts - 1).min(10));
+                    let error = err.to_string();
+                    sqlx::query!(
+                        r#"
+                        UPDATE mail_queue
+                        SET status = ?, attempts = ?, last_error = ?,
+                            next_attempt_at = datetime('now', ?)
+                        WHERE id = ?
+                        "#,
+                        status,
+                        attempts,
+                        error,
+                        delay,
+                        row.id
+                    )
+                    .execute(&self.pool)
+                    .await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
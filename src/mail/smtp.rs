@@ -0,0 +1,48 @@
+use anyhow::{bail, Context};
+use axum::async_trait;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Tokio1Executor,
+};
+
+use super::{build_message, Mailer, OutgoingEmail};
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    // SMTP_HOST, SMTP_PORT, SMTP_SECURITY (starttls, tls or none),
+    // SMTP_USERNAME and SMTP_PASSWORD
+    pub fn from_env(from: Mailbox) -> anyhow::Result<Self> {
+        let host = std::env::var("SMTP_HOST").context("SMTP_HOST must be set")?;
+        let mut builder = match std::env::var("SMTP_SECURITY").as_deref() {
+            Err(_) | Ok("starttls") => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)?,
+            Ok("tls") => AsyncSmtpTransport::<Tokio1Executor>::relay(&host)?,
+            Ok("none") => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host),
+            Ok(other) => bail!("Unsupported SMTP_SECURITY `{other}`"),
+        };
+        if let Ok(port) = std::env::var("SMTP_PORT") {
+            builder = builder.port(port.parse().context("SMTP_PORT is not a number")?);
+        }
+        if let Ok(username) = std::env::var("SMTP_USERNAME") {
+            let password = std::env::var("SMTP_PASSWORD").unwrap_or_default();
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(SmtpMailer {
+            transport: builder.build(),
+            from,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &OutgoingEmail) -> anyhow::Result<()> {
+        let message = build_message(&self.from, email)?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
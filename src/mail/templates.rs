@@ -0,0 +1,49 @@
+// Subjects and bodies of every email we send
+
+pub enum Template {
+    Verification { link: String },
+    PasswordReset { link: String },
+    EmailChange { link: String },
+    Notification { subject: String, message: String },
+}
+
+impl Template {
+    // Returns (subject, body)
+    pub fn render(&self) -> (String, String) {
+        match self {
+            Template::Verification { link } => (
+                "Please verify your email address".to_string(),
+                format!(
+                    "Welcome to yRealEstate!\n\n\
+                     Please confirm your email address by opening the link below:\n\n\
+                     {link}\n\n\
+                     The link is valid for 24 hours. If you did not create an account, \
+                     you can ignore this email."
+                ),
+            ),
+            Template::PasswordReset { link } => (
+                "Reset your password".to_string(),
+                format!(
+                    "Someone asked to reset the password of your yRealEstate account.\n\n\
+                     Open the link below to choose a new password:\n\n\
+                     {link}\n\n\
+                     The link is valid for 1 hour. If you did not ask for this, \
+                     you can ignore this email."
+                ),
+            ),
+            Template::EmailChange { link } => (
+                "Confirm your new email address".to_string(),
+                format!(
+                    "Someone asked to use this address for their yRealEstate account.\n\n\
+                     Open the link below to confirm the change:\n\n\
+                     {link}\n\n\
+                     The link is valid for 24 hours. If you did not ask for this, \
+                     you can ignore this email."
+                ),
+            ),
+            Template::Notification { subject, message } => {
+                (subject.clone(), format!("{message}\n\n-- \nyRealEstate"))
+            }
+        }
+    }
+}
@@ -1,17 +1,43 @@
-use auth::{require_role, RequireRole, Role};
+use auth::{require_permission, Permission, RequirePermission};
 use axum::{
-    middleware::{self, from_fn_with_state},
-    routing::{get, post},
+    middleware,
+    routing::{delete, get, post, put},
     Router,
 };
-use sqlx::SqlitePool;
-use std::net::SocketAddr;
-use tower_http::cors::CorsLayer;
+use config::Config;
+use cors::CorsPolicy;
+use keyring::KeyRing;
+use mail::MailQueue;
+use oidc::OidcClient;
+use sqlx::{sqlite::SqliteConnectOptions, ConnectOptions, Connection, SqlitePool};
+use state::AppState;
+use std::{net::SocketAddr, str::FromStr, sync::Arc};
 
+mod access;
+mod api_keys;
+mod audit;
 mod auth;
+mod client_info;
+mod config;
+mod cookies;
+mod cors;
 mod error;
+mod etag;
+mod geo;
 mod handlers;
+mod keyring;
+mod login_attempts;
+mod mail;
+mod mfa;
 mod models;
+mod oidc;
+mod password;
+mod password_policy;
+mod property_map;
+mod property_search;
+mod state;
+mod tokens;
+mod verification;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -21,14 +47,52 @@ async fn main() -> anyhow::Result<()> {
     let database_url =
         std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:db/realestate.db".to_string());
 
+    // Refuse to start without real signing keys
+    let keys = KeyRing::from_env()?;
+    let config = Config::from_env()?;
+    let oidc = OidcClient::from_env(&config)?;
+    let cors = Arc::new(CorsPolicy::from_env(&config)?);
+
+    // Migrations get their own connection with foreign keys off so tables
+    // can be rebuilt, SQLite can't change constraints in place
+    let mut conn = SqliteConnectOptions::from_str(&database_url)?
+        .foreign_keys(false)
+        .connect()
+        .await?;
+    sqlx::migrate!("./migrations").run(&mut conn).await?;
+    conn.close().await?;
+
     let pool = SqlitePool::connect(&database_url).await?;
 
-    sqlx::migrate!("./migrations").run(&pool).await?;
+    let mail = MailQueue::new(pool.clone());
+    mail.spawn_worker(mail::mailer_from_env(&pool)?);
+
+    let state = AppState {
+        pool: pool.clone(),
+        keys: Arc::new(keys),
+        config: Arc::new(config),
+        mail: mail.clone(),
+        verification: Arc::new(mail),
+        login_attempts: login_attempts::attempt_store_from_env(&pool)?,
+        oidc: Arc::new(oidc),
+    };
 
     let app = Router::new()
         // Public routes
+        .route("/.well-known/jwks.json", get(handlers::jwks))
         .route("/api/login", post(handlers::login))
+        .route("/api/login/mfa", post(handlers::login_mfa))
+        .route("/api/login/mfa/enroll", post(handlers::login_mfa_enroll))
         .route("/api/refresh", post(handlers::refresh_token))
+        .route(
+            "/api/oidc/:provider/authorize",
+            get(handlers::oidc_authorize),
+        )
+        .route(
+            "/api/oidc/:provider/callback",
+            post(handlers::oidc_callback),
+        )
+        .route("/api/oidc/:provider/link", post(handlers::oidc_link))
         .route(
             "/api/password-reset",
             post(handlers::request_password_reset),
@@ -37,28 +101,154 @@ async fn main() -> anyhow::Result<()> {
             "/api/password-reset/confirm",
             post(handlers::reset_password),
         )
-        // Protected routes with role-based auth
+        // Protected routes with permission checks
         .route(
             "/api/admin/users",
             get(handlers::list_users).route_layer(middleware::from_fn_with_state(
-                RequireRole(Role::Admin),
-                require_role,
+                RequirePermission {
+                    app: state.clone(),
+                    permission: Permission::UserList,
+                },
+                require_permission,
+            )),
+        )
+        .route(
+            "/api/admin/users/:id/unlock",
+            post(handlers::unlock_user).route_layer(middleware::from_fn_with_state(
+                RequirePermission {
+                    app: state.clone(),
+                    permission: Permission::UserUnlock,
+                },
+                require_permission,
+            )),
+        )
+        .route(
+            "/api/admin/users/:id/role",
+            put(handlers::update_user_role).route_layer(middleware::from_fn_with_state(
+                RequirePermission {
+                    app: state.clone(),
+                    permission: Permission::UserUpdateRole,
+                },
+                require_permission,
+            )),
+        )
+        .route(
+            "/api/admin/users/:id/impersonate",
+            post(handlers::impersonate_user).route_layer(middleware::from_fn_with_state(
+                RequirePermission {
+                    app: state.clone(),
+                    permission: Permission::UserImpersonate,
+                },
+                require_permission,
+            )),
+        )
+        .route(
+            "/api/admin/audit",
+            get(handlers::list_audit_events).route_layer(middleware::from_fn_with_state(
+                RequirePermission {
+                    app: state.clone(),
+                    permission: Permission::AuditRead,
+                },
+                require_permission,
+            )),
+        )
+        .route(
+            "/api/admin/audit/export",
+            get(handlers::export_audit_events).route_layer(middleware::from_fn_with_state(
+                RequirePermission {
+                    app: state.clone(),
+                    permission: Permission::AuditRead,
+                },
+                require_permission,
+            )),
+        )
+        .route(
+            "/api/admin/mfa-policies",
+            get(handlers::list_mfa_policies).route_layer(middleware::from_fn_with_state(
+                RequirePermission {
+                    app: state.clone(),
+                    permission: Permission::MfaPolicyManage,
+                },
+                require_permission,
+            )),
+        )
+        .route(
+            "/api/admin/mfa-policies/:role",
+            put(handlers::update_mfa_policy).route_layer(middleware::from_fn_with_state(
+                RequirePermission {
+                    app: state.clone(),
+                    permission: Permission::MfaPolicyManage,
+                },
+                require_permission,
             )),
         )
         .route(
             "/api/properties/create",
             post(handlers::create_property).route_layer(middleware::from_fn_with_state(
-                RequireRole(Role::Agent),
-                require_role,
+                RequirePermission {
+                    app: state.clone(),
+                    permission: Permission::PropertyCreate,
+                },
+                require_permission,
             )),
         )
+        // Session routes
+        .route("/api/logout", post(handlers::logout))
+        .route("/api/logout/all", post(handlers::logout_all))
+        .route("/api/sessions", get(handlers::list_sessions))
+        .route("/api/sessions/:id", delete(handlers::revoke_session))
+        // API key routes
+        .route(
+            "/api/api-keys",
+            get(handlers::list_api_keys).post(handlers::create_api_key),
+        )
+        .route("/api/api-keys/:id", delete(handlers::revoke_api_key))
+        // Two-factor routes
+        .route("/api/mfa", delete(handlers::disable_mfa))
+        .route("/api/mfa/enroll", post(handlers::enroll_mfa))
+        .route("/api/mfa/confirm", post(handlers::confirm_mfa))
+        .route(
+            "/api/mfa/recovery-codes",
+            post(handlers::regenerate_recovery_codes),
+        )
         // User routes
+        .route("/api/me/password", put(handlers::change_password))
+        .route("/api/me/email", post(handlers::request_email_change))
+        .route(
+            "/api/me/email/confirm",
+            post(handlers::confirm_email_change),
+        )
         .route("/api/users", post(handlers::create_user))
+        .route("/api/users/verify", post(handlers::verify_email))
+        .route(
+            "/api/users/verify/resend",
+            post(handlers::resend_verification),
+        )
         .route("/api/users/:id", get(handlers::get_user))
-        .route("/api/users/by-role/:role", get(handlers::get_users_by_role))
+        .route(
+            "/api/users/by-role/:role",
+            get(handlers::get_users_by_role).route_layer(middleware::from_fn_with_state(
+                RequirePermission {
+                    app: state.clone(),
+                    permission: Permission::UserList,
+                },
+                require_permission,
+            )),
+        )
         // Property routes
         .route("/api/properties", get(handlers::list_properties))
-        .route("/api/properties/:id", get(handlers::get_property))
+        .route("/api/properties/map", get(handlers::properties_map))
+        .route(
+            "/api/properties/:id",
+            get(handlers::get_property)
+                .put(handlers::update_property)
+                .patch(handlers::patch_property)
+                .delete(handlers::delete_property),
+        )
+        .route(
+            "/api/properties/:id/restore",
+            post(handlers::restore_property),
+        )
         // Message routes
         .route("/api/conversations", post(handlers::create_conversation))
         .route(
@@ -73,13 +263,12 @@ async fn main() -> anyhow::Result<()> {
             "/api/users/:id/conversations",
             get(handlers::get_user_conversations),
         )
-        .with_state(pool)
-        .layer(
-            CorsLayer::new()
-                .allow_origin(tower_http::cors::Any)
-                .allow_methods(tower_http::cors::Any)
-                .allow_headers(tower_http::cors::Any),
-        );
+        .with_state(state)
+        .layer(cors.layer())
+        .layer(middleware::from_fn_with_state(
+            cors.clone(),
+            cors::reject_unknown_origins,
+        ));
 
     let port = std::env::var("PORT")
         .ok()
@@ -88,7 +277,11 @@ async fn main() -> anyhow::Result<()> {
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
     tracing::info!("listening on {}", addr);
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
@@ -0,0 +1,352 @@
+// TOTP second factor, recovery codes and login challenges
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sqlx::SqlitePool;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::error::ApiError;
+use crate::models::{MfaEnrollment, User};
+use crate::tokens::{hash_token, new_token};
+
+const STEP: u64 = 30;
+const DIGITS: usize = 6;
+const RECOVERY_CODE_COUNT: usize = 10;
+pub const MAX_CHALLENGE_ATTEMPTS: i64 = 5;
+
+// The issuer and account only end up in the otpauth URI, the codes
+// depend on the secret alone
+fn totp(secret: &str, issuer: Option<&str>, account: &str) -> Result<TOTP, ApiError> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|_| ApiError::ValidationError("Invalid MFA secret".to_string()))?;
+    TOTP::new(
+        Algorithm::SHA1,
+        DIGITS,
+        1,
+        STEP,
+        secret,
+        issuer.map(str::to_string),
+        account.to_string(),
+    )
+    .map_err(|_| ApiError::ValidationError("Invalid MFA secret".to_string()))
+}
+
+// The time step the code belongs to, allowing one step of clock drift
+fn matching_step(totp: &TOTP, code: &str) -> Option<i64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let current = now / STEP;
+    [current - 1, current, current + 1]
+        .into_iter()
+        .find(|step| totp.generate(step * STEP) == code.trim())
+        .map(|step| step as i64)
+}
+
+// Returns Some(enrollment_required) when the login needs a second factor
+pub async fn login_requirement(pool: &SqlitePool, user: &User) -> Result<Option<bool>, ApiError> {
+    if is_enabled(pool, user.id.unwrap()).await? {
+        return Ok(Some(false));
+    }
+    if is_required_for_role(pool, &user.role.to_string()).await? {
+        return Ok(Some(true));
+    }
+    Ok(None)
+}
+
+pub async fn is_required_for_role(pool: &SqlitePool, role: &str) -> Result<bool, ApiError> {
+    let required = sqlx::query_scalar!(
+        r#"
+        SELECT required as "required: bool"
+        FROM mfa_role_policies
+        WHERE role = ?
+        "#,
+        role
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    Ok(required.unwrap_or(false))
+}
+
+pub async fn is_enabled(pool: &SqlitePool, user_id: i64) -> Result<bool, ApiError> {
+    let enabled = sqlx::query_scalar!(
+        r#"
+        SELECT enabled as "enabled: bool"
+        FROM user_mfa
+        WHERE user_id = ?
+        "#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    Ok(enabled.unwrap_or(false))
+}
+
+// Stores a fresh, not yet confirmed secret, replacing any pending one
+pub async fn start_enrollment(
+    pool: &SqlitePool,
+    issuer: &str,
+    user_id: i64,
+    email: &str,
+) -> Result<MfaEnrollment, ApiError> {
+    if is_enabled(pool, user_id).await? {
+        return Err(ApiError::ValidationError(
+            "Two-factor authentication is already enabled".to_string(),
+        ));
+    }
+
+    let Secret::Encoded(secret) = Secret::generate_secret().to_encoded() else {
+        unreachable!("to_encoded always returns an encoded secret")
+    };
+    let otpauth_uri = totp(&secret, Some(issuer), email)?.get_url();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO user_mfa (user_id, secret, enabled)
+        VALUES (?, ?, false)
+        ON CONFLICT (user_id) DO UPDATE
+        SET secret = excluded.secret, last_used_step = NULL, created_at = CURRENT_TIMESTAMP
+        "#,
+        user_id,
+        secret
+    )
+    .execute(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    Ok(MfaEnrollment {
+        secret,
+        otpauth_uri,
+    })
+}
+
+// Enables MFA once the user proves their app works and returns the
+// recovery codes, which are shown exactly once
+pub async fn confirm_enrollment(
+    pool: &SqlitePool,
+    user_id: i64,
+    code: &str,
+) -> Result<Option<Vec<String>>, ApiError> {
+    let pending = sqlx::query!(
+        r#"
+        SELECT secret
+        FROM user_mfa
+        WHERE user_id = ? AND enabled = false
+        "#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .ok_or_else(|| ApiError::ValidationError("No two-factor enrollment in progress".to_string()))?;
+
+    let Some(step) = matching_step(&totp(&pending.secret, None, "")?, code) else {
+        return Ok(None);
+    };
+
+    let mut tx = pool.begin().await.map_err(ApiError::DatabaseError)?;
+    sqlx::query!(
+        r#"
+        UPDATE user_mfa
+        SET enabled = true, last_used_step = ?, confirmed_at = CURRENT_TIMESTAMP
+        WHERE user_id = ?
+        "#,
+        step,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+    let codes = replace_recovery_codes(&mut tx, user_id).await?;
+    tx.commit().await.map_err(ApiError::DatabaseError)?;
+
+    Ok(Some(codes))
+}
+
+async fn replace_recovery_codes(
+    tx: &mut sqlx::SqliteConnection,
+    user_id: i64,
+) -> Result<Vec<String>, ApiError> {
+    sqlx::query!("DELETE FROM mfa_recovery_codes WHERE user_id = ?", user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(ApiError::DatabaseError)?;
+
+    let mut codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let raw = new_token();
+        let code = format!("{}-{}", &raw[..5], &raw[5..10]);
+        let code_hash = hash_token(&code);
+        sqlx::query!(
+            r#"
+            INSERT INTO mfa_recovery_codes (user_id, code_hash)
+            VALUES (?, ?)
+            "#,
+            user_id,
+            code_hash
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(ApiError::DatabaseError)?;
+        codes.push(code);
+    }
+
+    Ok(codes)
+}
+
+// Checks a TOTP code or, failing that, burns a recovery code. A TOTP code
+// is only accepted once.
+pub async fn verify(pool: &SqlitePool, user_id: i64, code: &str) -> Result<bool, ApiError> {
+    let mfa = sqlx::query!(
+        r#"
+        SELECT secret
+        FROM user_mfa
+        WHERE user_id = ? AND enabled = true
+        "#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .ok_or_else(|| {
+        ApiError::ValidationError("Two-factor authentication is not enabled".to_string())
+    })?;
+
+    if let Some(step) = matching_step(&totp(&mfa.secret, None, "")?, code) {
+        let accepted = sqlx::query!(
+            r#"
+            UPDATE user_mfa
+            SET last_used_step = ?
+            WHERE user_id = ? AND (last_used_step IS NULL OR last_used_step < ?)
+            "#,
+            step,
+            user_id,
+            step
+        )
+        .execute(pool)
+        .await
+        .map_err(ApiError::DatabaseError)?
+        .rows_affected();
+        return Ok(accepted > 0);
+    }
+
+    let code_hash = hash_token(code.trim());
+    let used = sqlx::query!(
+        r#"
+        UPDATE mfa_recovery_codes
+        SET used_at = CURRENT_TIMESTAMP
+        WHERE user_id = ? AND code_hash = ? AND used_at IS NULL
+        "#,
+        user_id,
+        code_hash
+    )
+    .execute(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .rows_affected();
+
+    Ok(used > 0)
+}
+
+pub async fn regenerate_recovery_codes(
+    pool: &SqlitePool,
+    user_id: i64,
+) -> Result<Vec<String>, ApiError> {
+    let mut tx = pool.begin().await.map_err(ApiError::DatabaseError)?;
+    let codes = replace_recovery_codes(&mut tx, user_id).await?;
+    tx.commit().await.map_err(ApiError::DatabaseError)?;
+
+    Ok(codes)
+}
+
+pub async fn disable(pool: &SqlitePool, user_id: i64) -> Result<(), ApiError> {
+    let mut tx = pool.begin().await.map_err(ApiError::DatabaseError)?;
+    sqlx::query!("DELETE FROM mfa_recovery_codes WHERE user_id = ?", user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(ApiError::DatabaseError)?;
+    sqlx::query!("DELETE FROM user_mfa WHERE user_id = ?", user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(ApiError::DatabaseError)?;
+    tx.commit().await.map_err(ApiError::DatabaseError)?;
+
+    Ok(())
+}
+
+pub async fn create_challenge(pool: &SqlitePool, user_id: i64) -> Result<String, ApiError> {
+    let token = new_token();
+    let token_hash = hash_token(&token);
+
+    sqlx::query!(
+        r#"
+        INSERT INTO mfa_challenges (user_id, token_hash, expires_at)
+        VALUES (?, ?, datetime('now', '+5 minutes'))
+        "#,
+        user_id,
+        token_hash
+    )
+    .execute(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    Ok(token)
+}
+
+pub struct Challenge {
+    pub id: i64,
+    pub user_id: i64,
+}
+
+pub async fn find_challenge(pool: &SqlitePool, token: &str) -> Result<Challenge, ApiError> {
+    let token_hash = hash_token(token);
+
+    sqlx::query_as!(
+        Challenge,
+        r#"
+        SELECT id as "id!", user_id
+        FROM mfa_challenges
+        WHERE token_hash = ? AND used_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+        AND attempts < ?
+        "#,
+        token_hash,
+        MAX_CHALLENGE_ATTEMPTS
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .ok_or_else(|| ApiError::AuthenticationError("Invalid or expired MFA challenge".to_string()))
+}
+
+pub async fn record_failed_attempt(pool: &SqlitePool, challenge_id: i64) -> Result<(), ApiError> {
+    sqlx::query!(
+        "UPDATE mfa_challenges SET attempts = attempts + 1 WHERE id = ?",
+        challenge_id
+    )
+    .execute(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    Ok(())
+}
+
+// Returns false when another request already used the challenge
+pub async fn complete_challenge(pool: &SqlitePool, challenge_id: i64) -> Result<bool, ApiError> {
+    let completed = sqlx::query!(
+        r#"
+        UPDATE mfa_challenges
+        SET used_at = CURRENT_TIMESTAMP
+        WHERE id = ? AND used_at IS NULL
+        "#,
+        challenge_id
+    )
+    .execute(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .rows_affected();
+
+    Ok(completed > 0)
+}
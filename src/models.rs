@@ -2,10 +2,11 @@ use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 
 // ------------- User --------------------
-#[derive(Debug, Serialize, Deserialize, sqlx::Type)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type)]
 #[sqlx(type_name = "TEXT", rename_all = "lowercase")]
 #[serde(rename_all = "lowercase")]
 pub enum UserRole {
+    Admin,
     Seller,
     Buyer,
     Owner,
@@ -18,6 +19,7 @@ impl std::fmt::Display for UserRole {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         // Convert enum variant to lowercase string
         match self {
+            UserRole::Admin => write!(f, "admin"),
             UserRole::Seller => write!(f, "seller"),
             UserRole::Buyer => write!(f, "buyer"),
             UserRole::Owner => write!(f, "owner"),
@@ -36,6 +38,28 @@ pub struct NewUser {
     pub role: UserRole,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct PasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmailChangeRequest {
+    pub new_email: String,
+    pub current_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmailChangeConfirm {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoleUpdate {
+    pub role: UserRole,
+}
+
 #[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct User {
     pub id: Option<i64>,
@@ -49,12 +73,10 @@ pub struct User {
     pub profile_image_url: Option<String>,
     pub created_at: Option<String>,
     pub updated_at: Option<String>,
-    pub reset_token: Option<String>,
-    pub reset_token_expires: Option<String>,
 }
 
 // ------------- Properties --------------------
-#[derive(Debug, Serialize, Deserialize, sqlx::Type)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type)]
 #[sqlx(type_name = "TEXT", rename_all = "lowercase")]
 #[serde(rename_all = "lowercase")]
 pub enum PropertyType {
@@ -64,7 +86,7 @@ pub enum PropertyType {
     Commercial,
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::Type)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type)]
 #[sqlx(type_name = "TEXT", rename_all = "lowercase")]
 #[serde(rename_all = "lowercase")]
 pub enum ListingType {
@@ -72,7 +94,7 @@ pub enum ListingType {
     Rent,
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::Type)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type)]
 #[sqlx(type_name = "TEXT", rename_all = "lowercase")]
 #[serde(rename_all = "lowercase")]
 pub enum PropertyStatus {
@@ -92,7 +114,7 @@ pub enum ViewingStatus {
     Cancelled,
 }
 
-#[derive(Debug, Serialize, Deserialize, FromRow)]
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
 pub struct Property {
     pub id: Option<i64>,
     pub title: String,
@@ -105,10 +127,196 @@ pub struct Property {
     pub property_type: PropertyType,
     pub listing_type: ListingType,
     pub status: PropertyStatus,
+    // Taken from the token on create
+    #[serde(default)]
     pub owner_id: i64,
     pub agent_id: Option<i64>,
+    #[serde(default)]
+    pub latitude: Option<f64>,
+    #[serde(default)]
+    pub longitude: Option<f64>,
     pub created_at: Option<String>,
     pub updated_at: Option<String>,
+    // Sent as the ETag instead
+    #[serde(skip)]
+    pub version: i64,
+}
+
+// Partial update of a listing. Fields left out are kept, nullable fields
+// are cleared with an explicit null. The others are read as nullable too,
+// so a null there is refused instead of ignored.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PropertyPatch {
+    #[serde(default, deserialize_with = "nullable")]
+    pub title: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub price: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub description: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub location: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub bedrooms: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub bathrooms: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub square_feet: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub property_type: Option<Option<PropertyType>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub listing_type: Option<Option<ListingType>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub status: Option<Option<PropertyStatus>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub agent_id: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub latitude: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub longitude: Option<Option<f64>>,
+}
+
+impl PropertyPatch {
+    // Returns the name of the first required field that was set to null
+    pub fn apply(self, property: &mut Property) -> Result<(), &'static str> {
+        fn set<T>(field: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+        fn set_required<T>(
+            field: &mut T,
+            value: Option<Option<T>>,
+            name: &'static str,
+        ) -> Result<(), &'static str> {
+            match value {
+                Some(None) => Err(name),
+                value => {
+                    set(field, value.flatten());
+                    Ok(())
+                }
+            }
+        }
+
+        set_required(&mut property.title, self.title, "title")?;
+        set_required(&mut property.price, self.price, "price")?;
+        set(&mut property.description, self.description);
+        set_required(&mut property.location, self.location, "location")?;
+        set(&mut property.bedrooms, self.bedrooms);
+        set(&mut property.bathrooms, self.bathrooms);
+        set(&mut property.square_feet, self.square_feet);
+        set_required(
+            &mut property.property_type,
+            self.property_type,
+            "property_type",
+        )?;
+        set_required(
+            &mut property.listing_type,
+            self.listing_type,
+            "listing_type",
+        )?;
+        set_required(&mut property.status, self.status, "status")?;
+        set(&mut property.agent_id, self.agent_id);
+        set(&mut property.latitude, self.latitude);
+        set(&mut property.longitude, self.longitude);
+
+        Ok(())
+    }
+}
+
+// Tells a null apart from a missing field, which serde's default maps to
+// None
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+// A search result. The snippet is set when searching by text, the
+// distance when searching around a point.
+#[derive(Debug, Serialize, FromRow)]
+pub struct PropertyHit {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub property: Property,
+    #[serde(skip)]
+    pub score: Option<f64>,
+    #[serde(skip)]
+    pub haversine: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[sqlx(skip)]
+    pub distance_km: Option<f64>,
+    // Escaped HTML with the matched words in <mark>
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PropertyPage {
+    pub properties: Vec<PropertyHit>,
+    // Matches across all pages
+    pub total: i64,
+    // Pass as `cursor` to get the next page
+    pub next_cursor: Option<String>,
+}
+
+// One grid cell of the map. With a single listing in it, the listing
+// fields are that listing's; otherwise they belong to any one of them.
+#[derive(Debug, FromRow)]
+pub struct MapCell {
+    pub count: i64,
+    pub min_price: f64,
+    pub max_price: f64,
+    // Mean position of the listings
+    pub latitude: f64,
+    pub longitude: f64,
+    pub id: i64,
+    pub title: String,
+    pub property_type: PropertyType,
+    pub listing_type: ListingType,
+    pub status: PropertyStatus,
+}
+
+// GeoJSON (RFC 7946), only the parts the map uses
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename = "FeatureCollection")]
+pub struct FeatureCollection {
+    pub features: Vec<Feature>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename = "Feature")]
+pub struct Feature {
+    pub geometry: PointGeometry,
+    pub properties: MapFeature,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename = "Point")]
+pub struct PointGeometry {
+    // Longitude first
+    pub coordinates: [f64; 2],
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum MapFeature {
+    Listing {
+        id: i64,
+        title: String,
+        price: f64,
+        property_type: PropertyType,
+        listing_type: ListingType,
+        status: PropertyStatus,
+    },
+    Cluster {
+        cluster: bool,
+        count: i64,
+        min_price: f64,
+        max_price: f64,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
@@ -167,7 +375,6 @@ pub struct NewConversation {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NewMessage {
-    pub sender_id: i64,
     pub content: String,
 }
 
@@ -182,8 +389,179 @@ pub struct ConversationDetails {
 
 // -------------- Authentication -----------
 
+#[derive(Debug, Serialize)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub expires_at: String,
+    pub last_used_at: Option<String>,
+    pub revoked_at: Option<String>,
+    pub created_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewApiKey {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_in_days: Option<i64>,
+}
+
+// The key itself is only ever returned here
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct Session {
+    pub id: String,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: Option<String>,
+    pub last_used_at: Option<String>,
+    pub current: bool,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct LoginCredentials {
     pub email: String,
     pub password: String,
 }
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OidcAuthorization {
+    pub authorization_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OidcCallback {
+    pub code: String,
+    pub state: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MfaEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaCodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaChallengeRequest {
+    pub challenge_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaLoginRequest {
+    pub challenge_token: String,
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct MfaPolicy {
+    pub role: String,
+    pub required: bool,
+    pub updated_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaPolicyUpdate {
+    pub required: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn property() -> Property {
+        serde_json::from_value(serde_json::json!({
+            "title": "Loft",
+            "price": 250000.0,
+            "description": "Bright",
+            "location": "Berlin",
+            "bedrooms": 2,
+            "bathrooms": 1,
+            "square_feet": 900.0,
+            "property_type": "apartment",
+            "listing_type": "sale",
+            "status": "active",
+            "agent_id": 4,
+            "latitude": 52.5,
+            "longitude": 13.4,
+            "id": 1,
+            "created_at": null,
+            "updated_at": null
+        }))
+        .unwrap()
+    }
+
+    fn patch(json: serde_json::Value) -> PropertyPatch {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn missing_fields_are_kept() {
+        let mut property = property();
+        patch(serde_json::json!({ "price": 200000.0 }))
+            .apply(&mut property)
+            .unwrap();
+        assert_eq!(property.price, 200000.0);
+        assert_eq!(property.title, "Loft");
+        assert_eq!(property.description.as_deref(), Some("Bright"));
+        assert_eq!(property.agent_id, Some(4));
+        assert_eq!(property.status, PropertyStatus::Active);
+    }
+
+    #[test]
+    fn null_clears_an_optional_field() {
+        let mut property = property();
+        patch(serde_json::json!({ "description": null, "agent_id": null, "status": "sold" }))
+            .apply(&mut property)
+            .unwrap();
+        assert_eq!(property.description, None);
+        assert_eq!(property.agent_id, None);
+        assert_eq!(property.bedrooms, Some(2));
+        assert_eq!(property.status, PropertyStatus::Sold);
+    }
+
+    #[test]
+    fn null_is_refused_for_a_required_field() {
+        for field in [
+            "title",
+            "price",
+            "location",
+            "property_type",
+            "listing_type",
+            "status",
+        ] {
+            let mut property = property();
+            let result = patch(serde_json::json!({ field: null })).apply(&mut property);
+            assert_eq!(result, Err(field));
+        }
+    }
+
+    #[test]
+    fn unknown_fields_are_refused() {
+        assert!(
+            serde_json::from_value::<PropertyPatch>(serde_json::json!({ "owner_id": 2 })).is_err()
+        );
+    }
+}
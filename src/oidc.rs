@@ -0,0 +1,533 @@
+// Sign in with external OpenID Connect providers (authorization code flow
+// with PKCE)
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::Context;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use tokio::sync::RwLock;
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::models::{User, UserRole};
+use crate::tokens::{hash_token, new_token};
+
+pub struct ProviderConfig {
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub scopes: String,
+    pub redirect_uri: String,
+}
+
+// The parts of the discovery document we use
+#[derive(Debug, Clone, Deserialize)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+struct Provider {
+    config: ProviderConfig,
+    // Fetched on first use so a provider that is down doesn't stop startup
+    metadata: RwLock<Option<Metadata>>,
+    jwks: RwLock<Option<JwkSet>>,
+}
+
+pub struct OidcClient {
+    http: reqwest::Client,
+    providers: HashMap<String, Provider>,
+}
+
+// What we take from a verified ID token
+#[derive(Debug, Deserialize)]
+pub struct IdentityClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    // Some providers send this as a string
+    #[serde(default)]
+    email_verified: Option<serde_json::Value>,
+    pub name: Option<String>,
+    nonce: Option<String>,
+}
+
+impl IdentityClaims {
+    pub fn email_verified(&self) -> bool {
+        match &self.email_verified {
+            Some(serde_json::Value::Bool(verified)) => *verified,
+            Some(serde_json::Value::String(verified)) => verified == "true",
+            _ => false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+impl OidcClient {
+    // OIDC_PROVIDERS lists the provider names, each configured through
+    // OIDC_<NAME>_ISSUER, _CLIENT_ID and optionally _CLIENT_SECRET, _SCOPES
+    // and _REDIRECT_URI. Any issuer works, including a local mock IdP.
+    pub fn from_env(config: &Config) -> anyhow::Result<Self> {
+        let mut providers = HashMap::new();
+        let names = std::env::var("OIDC_PROVIDERS").unwrap_or_default();
+
+        for name in names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let name = name.to_lowercase();
+            let prefix = format!("OIDC_{}", name.to_uppercase());
+            let var = |suffix: &str| std::env::var(format!("{prefix}_{suffix}")).ok();
+
+            let provider = ProviderConfig {
+                issuer: var("ISSUER")
+                    .with_context(|| format!("{prefix}_ISSUER is not set"))?
+                    .trim_end_matches('/')
+                    .to_string(),
+                client_id: var("CLIENT_ID")
+                    .with_context(|| format!("{prefix}_CLIENT_ID is not set"))?,
+                client_secret: var("CLIENT_SECRET"),
+                scopes: var("SCOPES").unwrap_or_else(|| "openid email profile".to_string()),
+                redirect_uri: var("REDIRECT_URI")
+                    .unwrap_or_else(|| format!("{}/auth/callback/{}", config.app_url, name)),
+                name: name.clone(),
+            };
+
+            providers.insert(
+                name,
+                Provider {
+                    config: provider,
+                    metadata: RwLock::new(None),
+                    jwks: RwLock::new(None),
+                },
+            );
+        }
+
+        Ok(OidcClient {
+            http: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+            providers,
+        })
+    }
+
+    fn provider(&self, name: &str) -> Result<&Provider, ApiError> {
+        self.providers.get(name).ok_or(ApiError::NotFound)
+    }
+
+    async fn metadata(&self, provider: &Provider) -> Result<Metadata, ApiError> {
+        if let Some(metadata) = provider.metadata.read().await.as_ref() {
+            return Ok(metadata.clone());
+        }
+
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            provider.config.issuer
+        );
+        let metadata: Metadata = self.fetch_json(&url).await?;
+        if metadata.issuer.trim_end_matches('/') != provider.config.issuer {
+            return Err(ApiError::InternalError(format!(
+                "discovery document of {} names issuer {}",
+                provider.config.name, metadata.issuer
+            )));
+        }
+
+        *provider.metadata.write().await = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    async fn fetch_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, ApiError> {
+        self.http
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| ApiError::InternalError(format!("request to {url} failed: {err}")))?
+            .json()
+            .await
+            .map_err(|err| ApiError::InternalError(format!("invalid response from {url}: {err}")))
+    }
+
+    // Keys are refetched when a token names an unknown kid, which is how
+    // providers roll their keys
+    async fn signing_key(
+        &self,
+        provider: &Provider,
+        metadata: &Metadata,
+        kid: Option<&str>,
+    ) -> Result<Jwk, ApiError> {
+        let find = |jwks: &JwkSet| match kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            None if jwks.keys.len() == 1 => jwks.keys.first().cloned(),
+            None => None,
+        };
+
+        if let Some(key) = provider.jwks.read().await.as_ref().and_then(find) {
+            return Ok(key);
+        }
+
+        let jwks: JwkSet = self.fetch_json(&metadata.jwks_uri).await?;
+        let key = find(&jwks);
+        *provider.jwks.write().await = Some(jwks);
+
+        key.ok_or_else(|| ApiError::AuthenticationError("Unknown ID token signing key".to_string()))
+    }
+
+    // Starts a login and returns the URL to send the browser to
+    pub async fn authorization_url(
+        &self,
+        pool: &SqlitePool,
+        provider_name: &str,
+    ) -> Result<String, ApiError> {
+        let provider = self.provider(provider_name)?;
+        let metadata = self.metadata(provider).await?;
+
+        let state = new_token();
+        let nonce = new_token();
+        let code_verifier = format!("{}{}", new_token(), new_token());
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+        let state_hash = hash_token(&state);
+
+        sqlx::query!("DELETE FROM oidc_states WHERE expires_at < CURRENT_TIMESTAMP")
+            .execute(pool)
+            .await
+            .map_err(ApiError::DatabaseError)?;
+        sqlx::query!(
+            r#"
+            INSERT INTO oidc_states (state_hash, provider, nonce, code_verifier, expires_at)
+            VALUES (?, ?, ?, ?, datetime('now', '+10 minutes'))
+            "#,
+            state_hash,
+            provider.config.name,
+            nonce,
+            code_verifier
+        )
+        .execute(pool)
+        .await
+        .map_err(ApiError::DatabaseError)?;
+
+        let mut url = Url::parse(&metadata.authorization_endpoint).map_err(|err| {
+            ApiError::InternalError(format!("invalid authorization endpoint: {err}"))
+        })?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &provider.config.client_id)
+            .append_pair("redirect_uri", &provider.config.redirect_uri)
+            .append_pair("scope", &provider.config.scopes)
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &code_challenge)
+            .append_pair("code_challenge_method", "S256");
+
+        Ok(url.into())
+    }
+
+    // Redeems the code the provider redirected back with and verifies the
+    // ID token it returns
+    pub async fn complete(
+        &self,
+        pool: &SqlitePool,
+        provider_name: &str,
+        code: &str,
+        state: &str,
+    ) -> Result<IdentityClaims, ApiError> {
+        let provider = self.provider(provider_name)?;
+        let state_hash = hash_token(state);
+
+        // Each state can be used once, and only with the provider it was
+        // issued for
+        let pending = sqlx::query!(
+            r#"
+            DELETE FROM oidc_states
+            WHERE state_hash = ? AND provider = ? AND expires_at > CURRENT_TIMESTAMP
+            RETURNING nonce, code_verifier
+            "#,
+            state_hash,
+            provider.config.name
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(ApiError::DatabaseError)?
+        .ok_or_else(|| {
+            ApiError::AuthenticationError("Invalid or expired login state".to_string())
+        })?;
+
+        let metadata = self.metadata(provider).await?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", provider.config.redirect_uri.as_str()),
+            ("client_id", provider.config.client_id.as_str()),
+            ("code_verifier", pending.code_verifier.as_str()),
+        ];
+        if let Some(secret) = &provider.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+
+        let response = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .map_err(|err| ApiError::InternalError(format!("token request failed: {err}")))?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            tracing::warn!(
+                "{} rejected the authorization code ({}): {}",
+                provider.config.name,
+                status,
+                body
+            );
+            return Err(ApiError::AuthenticationError(
+                "The identity provider rejected the login".to_string(),
+            ));
+        }
+        let tokens: TokenResponse = response
+            .json()
+            .await
+            .map_err(|err| ApiError::InternalError(format!("invalid token response: {err}")))?;
+
+        let claims = self
+            .verify_id_token(provider, &metadata, &tokens.id_token)
+            .await?;
+        if claims.nonce.as_deref() != Some(pending.nonce.as_str()) {
+            return Err(ApiError::AuthenticationError(
+                "ID token nonce does not match".to_string(),
+            ));
+        }
+
+        Ok(claims)
+    }
+
+    async fn verify_id_token(
+        &self,
+        provider: &Provider,
+        metadata: &Metadata,
+        id_token: &str,
+    ) -> Result<IdentityClaims, ApiError> {
+        let invalid = || ApiError::AuthenticationError("Invalid ID token".to_string());
+
+        let header = decode_header(id_token).map_err(|_| invalid())?;
+        // Shared-secret signatures would let anyone holding the client
+        // secret mint tokens
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(invalid());
+        }
+
+        let jwk = self
+            .signing_key(provider, metadata, header.kid.as_deref())
+            .await?;
+        let key = DecodingKey::from_jwk(&jwk).map_err(|_| invalid())?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&provider.config.client_id]);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        decode::<IdentityClaims>(id_token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|err| {
+                tracing::warn!("rejected ID token from {}: {}", provider.config.name, err);
+                invalid()
+            })
+    }
+}
+
+// Finds the user linked to the identity. Otherwise links the verified
+// account with the same, provider-verified email, or signs up a new buyer.
+pub async fn find_or_create_user(
+    pool: &SqlitePool,
+    provider: &str,
+    claims: &IdentityClaims,
+) -> Result<User, ApiError> {
+    let mut tx = pool.begin().await.map_err(ApiError::DatabaseError)?;
+
+    let linked = sqlx::query_scalar!(
+        r#"
+        UPDATE user_identities
+        SET last_login_at = CURRENT_TIMESTAMP
+        WHERE provider = ? AND subject = ?
+        RETURNING user_id
+        "#,
+        provider,
+        claims.sub
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    let user_id = match linked {
+        Some(user_id) => user_id,
+        None => {
+            let email = claims
+                .email
+                .as_deref()
+                .ok_or_else(|| {
+                    ApiError::ValidationError(
+                        "The identity provider did not share an email address".to_string(),
+                    )
+                })?
+                .trim()
+                .to_lowercase();
+
+            let existing = sqlx::query!(
+                r#"
+                SELECT id as "id!", role as "role: UserRole", verified as "verified!: bool"
+                FROM users
+                WHERE lower(email) = ?
+                "#,
+                email
+            )
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(ApiError::DatabaseError)?;
+
+            let user_id = match existing {
+                // Linking on an unverified address would hand the account
+                // to whoever typed it in at the provider
+                Some(_) if !claims.email_verified() => {
+                    return Err(ApiError::ValidationError(
+                        "An account with this email already exists, sign in with your password"
+                            .to_string(),
+                    ));
+                }
+                // Anyone can sign up with an address they don't own, so an
+                // unverified account may belong to someone who still knows
+                // its password
+                Some(existing) if !existing.verified => {
+                    return Err(ApiError::ValidationError(
+                        "An unverified account with this email already exists, reset its \
+                         password to claim it"
+                            .to_string(),
+                    ));
+                }
+                // Admins link a provider themselves, while signed in
+                Some(existing) if existing.role == UserRole::Admin => {
+                    return Err(ApiError::ValidationError(
+                        "Sign in with your password and link this provider from your account"
+                            .to_string(),
+                    ));
+                }
+                Some(existing) => existing.id,
+                None => {
+                    let full_name = claims.name.as_deref().unwrap_or(&email);
+                    let verified = claims.email_verified();
+                    // No usable password until the user sets one via reset
+                    sqlx::query_scalar!(
+                        r#"
+                        INSERT INTO users (email, password_hash, full_name, role, verified)
+                        VALUES (?, '!', ?, ?, ?)
+                        RETURNING id as "id!"
+                        "#,
+                        email,
+                        full_name,
+                        UserRole::Buyer,
+                        verified
+                    )
+                    .fetch_one(&mut *tx)
+                    .await
+                    .map_err(ApiError::DatabaseError)?
+                }
+            };
+
+            sqlx::query!(
+                r#"
+                INSERT INTO user_identities (user_id, provider, subject, email, last_login_at)
+                VALUES (?, ?, ?, ?, CURRENT_TIMESTAMP)
+                "#,
+                user_id,
+                provider,
+                claims.sub,
+                email
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(ApiError::DatabaseError)?;
+
+            user_id
+        }
+    };
+
+    let user = sqlx::query_as!(
+        User,
+        r#"
+        SELECT id, email, password_hash, full_name, phone,
+               role as "role: UserRole", verified, profile_image_url,
+               created_at, updated_at
+        FROM users
+        WHERE id = ?
+        "#,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    tx.commit().await.map_err(ApiError::DatabaseError)?;
+
+    Ok(user)
+}
+
+// Links the identity to a signed-in user, whatever its email
+pub async fn link_identity(
+    pool: &SqlitePool,
+    user_id: i64,
+    provider: &str,
+    claims: &IdentityClaims,
+) -> Result<(), ApiError> {
+    let email = claims
+        .email
+        .as_deref()
+        .map(|email| email.trim().to_lowercase());
+
+    // Relinking the same identity is a no-op, taking over another user's
+    // returns no row
+    let linked = sqlx::query_scalar!(
+        r#"
+        INSERT INTO user_identities (user_id, provider, subject, email)
+        VALUES (?, ?, ?, ?)
+        ON CONFLICT (provider, subject) DO UPDATE
+        SET email = excluded.email
+        WHERE user_id = excluded.user_id
+        RETURNING user_id
+        "#,
+        user_id,
+        provider,
+        claims.sub,
+        email
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    if linked.is_none() {
+        return Err(ApiError::ValidationError(
+            "This identity is already linked to another account".to_string(),
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,98 @@
+// Password hashing. New hashes are Argon2id, bcrypt hashes from before the
+// switch are still accepted and replaced on the next login.
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+
+use crate::error::ApiError;
+
+// OWASP's baseline for Argon2id. Raising these makes every existing hash
+// outdated, which is picked up by `needs_rehash`.
+const MEMORY_KIB: u32 = 19 * 1024;
+const ITERATIONS: u32 = 2;
+const PARALLELISM: u32 = 1;
+
+// Hash of a random, discarded password with the parameters above. Logins for
+// unknown addresses verify against it so they take as long as real ones.
+const DUMMY_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$14qupweeePU7s8jI2fVBTw$IkrypQl/Km42GbyogyKAvnvCGPOh7V0mRnL3yHEFZWE";
+
+fn argon2() -> Argon2<'static> {
+    let params = Params::new(MEMORY_KIB, ITERATIONS, PARALLELISM, None)
+        .expect("password hashing parameters are valid");
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+}
+
+pub struct Verification {
+    pub valid: bool,
+    pub needs_rehash: bool,
+}
+
+// Hashing is deliberately slow, so it runs off the async workers
+pub async fn hash(password: &str) -> Result<String, ApiError> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        argon2()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+    })
+    .await
+    .map_err(|err| ApiError::InternalError(format!("password hashing task failed: {err}")))?
+    .map_err(|err| ApiError::InternalError(format!("password hashing failed: {err}")))
+}
+
+pub async fn verify(password: &str, hash: &str) -> Result<Verification, ApiError> {
+    let password = password.to_string();
+    let hash = hash.to_string();
+    tokio::task::spawn_blocking(move || verify_blocking(&password, &hash))
+        .await
+        .map_err(|err| ApiError::InternalError(format!("password verification task failed: {err}")))
+}
+
+// Same work as `verify`, for when there is no account to check against
+pub async fn verify_dummy(password: &str) -> Result<(), ApiError> {
+    verify(password, DUMMY_HASH).await?;
+    Ok(())
+}
+
+fn verify_blocking(password: &str, hash: &str) -> Verification {
+    if hash.starts_with("$2") {
+        return Verification {
+            valid: bcrypt::verify(password, hash).unwrap_or(false),
+            needs_rehash: true,
+        };
+    }
+
+    let Ok(parsed) = PasswordHash::new(hash) else {
+        return Verification {
+            valid: false,
+            needs_rehash: false,
+        };
+    };
+
+    Verification {
+        valid: argon2()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        needs_rehash: needs_rehash(&parsed),
+    }
+}
+
+fn needs_rehash(hash: &PasswordHash) -> bool {
+    if hash.algorithm != Algorithm::Argon2id.ident() || hash.version != Some(Version::V0x13.into())
+    {
+        return true;
+    }
+
+    match Params::try_from(hash) {
+        Ok(params) => {
+            params.m_cost() != MEMORY_KIB
+                || params.t_cost() != ITERATIONS
+                || params.p_cost() != PARALLELISM
+        }
+        Err(_) => true,
+    }
+}
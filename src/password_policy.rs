@@ -0,0 +1,90 @@
+// Rules a new password has to pass before it is hashed
+
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+use sha1::{Digest, Sha1};
+
+use crate::config::Config;
+use crate::error::ApiError;
+
+// SHA-1 hashes of common passwords, one uppercase hex hash per line, the
+// same format as the Have I Been Pwned downloads
+const BUNDLED_BREACHED: &str = include_str!("../data/breached_passwords.txt");
+
+fn bundled_breached() -> &'static HashSet<&'static str> {
+    static HASHES: OnceLock<HashSet<&'static str>> = OnceLock::new();
+    HASHES.get_or_init(|| {
+        BUNDLED_BREACHED
+            .lines()
+            .map(|line| line.split(':').next().unwrap_or_default().trim())
+            .filter(|hash| !hash.is_empty())
+            .collect()
+    })
+}
+
+pub async fn check(config: &Config, password: &str) -> Result<(), ApiError> {
+    let length = password.chars().count();
+    if length < config.password_min_length {
+        return Err(ApiError::ValidationError(format!(
+            "Password must be at least {} characters long",
+            config.password_min_length
+        )));
+    }
+    if length > config.password_max_length {
+        return Err(ApiError::ValidationError(format!(
+            "Password must be at most {} characters long",
+            config.password_max_length
+        )));
+    }
+
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+    if classes.iter().filter(|present| **present).count() < config.password_min_character_classes {
+        return Err(ApiError::ValidationError(format!(
+            "Password must use at least {} of: lowercase letters, uppercase letters, digits, symbols",
+            config.password_min_character_classes
+        )));
+    }
+
+    if is_breached(config, password).await {
+        return Err(ApiError::ValidationError(
+            "Password has appeared in a data breach, please choose another one".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+async fn is_breached(config: &Config, password: &str) -> bool {
+    let hash = format!("{:X}", Sha1::digest(password.as_bytes()));
+    if bundled_breached().contains(hash.as_str()) {
+        return true;
+    }
+
+    let Some(dir) = &config.password_breached_ranges_dir else {
+        return false;
+    };
+
+    // k-anonymity range files: one file per 5 character hash prefix, each
+    // line holding the rest of the hash and a count
+    let (prefix, suffix) = hash.split_at(5);
+    let path = std::path::Path::new(dir).join(format!("{prefix}.txt"));
+    match tokio::fs::read_to_string(&path).await {
+        Ok(range) => range.lines().any(|line| {
+            line.split(':')
+                .next()
+                .is_some_and(|candidate| candidate.trim().eq_ignore_ascii_case(suffix))
+        }),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => false,
+        // A broken list shouldn't stop everyone from signing up
+        Err(err) => {
+            tracing::warn!("could not read breached password range {:?}: {}", path, err);
+            false
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// Grid clustering of listings for the map. Below CLUSTER_MAX_ZOOM nearby
+// listings are merged into clusters so the map stays readable and the
+// response small; from there on every listing is its own feature. The
+// grouping itself runs in SQL, this module supplies the grid.
+
+use axum::{extract::Query, http::Uri};
+
+use crate::error::ApiError;
+use crate::geo::BoundingBox;
+use crate::models::{Feature, FeatureCollection, MapCell, MapFeature, PointGeometry};
+use crate::property_search::PropertySearch;
+
+const MAX_ZOOM: u8 = 22;
+pub const CLUSTER_MAX_ZOOM: u8 = 15;
+// Size of a grid cell on screen, in 256 px tile pixels
+const CELL_PIXELS: f64 = 64.0;
+// Largest bbox accepted on either side, in pixels at the requested zoom.
+// Bounds the number of cells, and of listings once they aren't clustered.
+const MAX_VIEW_PIXELS: f64 = 4096.0;
+// Web Mercator can't show the poles
+const MAX_LATITUDE: f64 = 85.051_128_78;
+
+// `zoom` only exists on the map, every other parameter is a list filter.
+// Splitting it off lets the filters share the list's strict parsing.
+pub fn parse_query(query: &str) -> Result<(u8, PropertySearch), ApiError> {
+    let mut zoom = None;
+    let mut filters = Vec::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        match pair.split_once('=') {
+            Some(("zoom", value)) => zoom = Some(value),
+            _ => filters.push(pair),
+        }
+    }
+
+    let zoom = zoom
+        .and_then(|zoom| zoom.parse::<u8>().ok())
+        .filter(|zoom| *zoom <= MAX_ZOOM)
+        .ok_or_else(|| {
+            ApiError::ValidationError(format!("zoom must be between 0 and {MAX_ZOOM}"))
+        })?;
+
+    let uri: Uri = format!("/?{}", filters.join("&"))
+        .parse()
+        .map_err(|_| ApiError::ValidationError("Invalid query string".to_string()))?;
+    let Query(search) = Query::<PropertySearch>::try_from_uri(&uri)
+        .map_err(|err| ApiError::ValidationError(err.body_text()))?;
+
+    Ok((zoom, search))
+}
+
+// The cells covering a bbox at a zoom level, in Web Mercator pixels. Columns
+// are a fixed width in degrees; rows get taller towards the poles, so
+// their edges are given as latitudes.
+#[derive(Debug)]
+pub struct Grid {
+    pub cell_degrees: f64,
+    pub first_row: i64,
+    // Southern edge of each row from first_row on, northernmost first.
+    // Anything south of the last one is in the row after it.
+    pub row_edges: Vec<f64>,
+}
+
+impl Grid {
+    pub fn new(bbox: &BoundingBox, zoom: u8) -> Result<Grid, ApiError> {
+        let world = world_pixels(zoom);
+
+        let mut longitudes = bbox.max_longitude - bbox.min_longitude;
+        if bbox.crosses_antimeridian() {
+            longitudes += 360.0;
+        }
+        let width = longitudes / 360.0 * world;
+        let top = y_pixel(bbox.max_latitude, world);
+        let bottom = y_pixel(bbox.min_latitude, world);
+        if width > MAX_VIEW_PIXELS || bottom - top > MAX_VIEW_PIXELS {
+            return Err(ApiError::ValidationError(format!(
+                "bbox is too large for zoom {zoom}, pass a smaller bbox or a lower zoom"
+            )));
+        }
+
+        let first_row = (top / CELL_PIXELS).floor() as i64;
+        let last_row = (bottom / CELL_PIXELS).floor() as i64;
+        let row_edges = (first_row..last_row)
+            .map(|row| latitude_at((row + 1) as f64 * CELL_PIXELS, world))
+            .collect();
+
+        Ok(Grid {
+            cell_degrees: 360.0 * CELL_PIXELS / world,
+            first_row,
+            row_edges,
+        })
+    }
+}
+
+fn world_pixels(zoom: u8) -> f64 {
+    256.0 * f64::from(1u32 << zoom)
+}
+
+fn y_pixel(latitude: f64, world: f64) -> f64 {
+    let latitude = latitude.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+    (1.0 - latitude.tan().asinh() / std::f64::consts::PI) / 2.0 * world
+}
+
+fn latitude_at(y: f64, world: f64) -> f64 {
+    (std::f64::consts::PI * (1.0 - 2.0 * y / world))
+        .sinh()
+        .atan()
+        .to_degrees()
+}
+
+pub fn feature_collection(cells: Vec<MapCell>) -> FeatureCollection {
+    FeatureCollection {
+        features: cells.into_iter().map(feature).collect(),
+    }
+}
+
+fn feature(cell: MapCell) -> Feature {
+    let properties = match cell.count {
+        1 => MapFeature::Listing {
+            id: cell.id,
+            title: cell.title,
+            price: cell.min_price,
+            property_type: cell.property_type,
+            listing_type: cell.listing_type,
+            status: cell.status,
+        },
+        count => MapFeature::Cluster {
+            cluster: true,
+            count,
+            min_price: cell.min_price,
+            max_price: cell.max_price,
+        },
+    };
+
+    Feature {
+        geometry: PointGeometry {
+            coordinates: [cell.longitude, cell.latitude],
+        },
+        properties,
+    }
+}
@@ -0,0 +1,640 @@
+// Full-text and geo search, filtering, sorting and keyset pagination for
+// the property listing
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+
+use crate::error::ApiError;
+use crate::geo::{self, BoundingBox, Point};
+use crate::models::{
+    ListingType, MapCell, Property, PropertyHit, PropertyPage, PropertyStatus, PropertyType,
+};
+use crate::property_map::{self, Grid};
+
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+// Listings on an unclustered map
+const MAX_MAP_LISTINGS: i64 = 1000;
+
+// Words of `q` beyond this are ignored
+const MAX_TERMS: usize = 16;
+
+// Brackets the matched words in snippets and are swapped for tags after
+// escaping. `reject_markers` keeps them out of listing text.
+const MATCH_START: char = '\u{E000}';
+const MATCH_END: char = '\u{E001}';
+
+const COLUMNS: &str = "properties.id, title, price, description, location, bedrooms, \
+                       bathrooms, square_feet, property_type, listing_type, status, owner_id, \
+                       agent_id, latitude, longitude, created_at, updated_at, version";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PropertySearch {
+    // Free text matched against title, description and location
+    pub q: Option<String>,
+    pub min_price: Option<f64>,
+    pub max_price: Option<f64>,
+    pub min_bedrooms: Option<i64>,
+    pub min_bathrooms: Option<i64>,
+    pub min_square_feet: Option<f64>,
+    pub max_square_feet: Option<f64>,
+    pub property_type: Option<PropertyType>,
+    pub listing_type: Option<ListingType>,
+    pub status: Option<PropertyStatus>,
+    // Case-insensitive substring of the location
+    pub location: Option<String>,
+    pub owner_id: Option<i64>,
+    pub agent_id: Option<i64>,
+    // Center for distances. Listings without coordinates are left out.
+    pub lat: Option<f64>,
+    pub lng: Option<f64>,
+    pub radius_km: Option<f64>,
+    // min_lng,min_lat,max_lng,max_lat
+    pub bbox: Option<String>,
+    // Relevance when searching by text, then distance from the center,
+    // otherwise newest first
+    pub sort: Option<SortField>,
+    // Nearest first for distance, otherwise descending
+    pub order: Option<SortOrder>,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    Relevance,
+    Distance,
+    #[default]
+    CreatedAt,
+    Price,
+    Bedrooms,
+    SquareFeet,
+}
+
+impl SortField {
+    // Unknown sizes sort as zero so the keyset comparison never sees NULL
+    fn expression(self) -> &'static str {
+        match self {
+            // bm25 scores better matches lower
+            SortField::Relevance => "-fts.score",
+            SortField::Distance => "haversine",
+            SortField::CreatedAt => "COALESCE(created_at, '')",
+            SortField::Price => "price",
+            SortField::Bedrooms => "COALESCE(bedrooms, 0)",
+            SortField::SquareFeet => "COALESCE(square_feet, 0)",
+        }
+    }
+
+    fn value(self, hit: &PropertyHit) -> Value {
+        let property = &hit.property;
+        match self {
+            SortField::Relevance => (-hit.score.unwrap_or_default()).into(),
+            SortField::Distance => hit.haversine.unwrap_or_default().into(),
+            SortField::CreatedAt => property.created_at.clone().unwrap_or_default().into(),
+            SortField::Price => property.price.into(),
+            SortField::Bedrooms => property.bedrooms.unwrap_or(0).into(),
+            SortField::SquareFeet => property.square_feet.unwrap_or(0.0).into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+// Position after the last row of a page. It names the sort it was made
+// for, so it can't be replayed against a different one.
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+    sort: SortField,
+    order: SortOrder,
+    value: Value,
+    id: i64,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(cursor: &str) -> Result<Cursor, ApiError> {
+        URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(|| ApiError::ValidationError("Invalid cursor".to_string()))
+    }
+}
+
+// The text and geo parameters in the form the queries need
+struct Criteria {
+    fts_match: Option<String>,
+    center: Option<Point>,
+    radius_km: Option<f64>,
+    bbox: Option<BoundingBox>,
+}
+
+impl PropertySearch {
+    fn sort(&self) -> SortField {
+        match self.sort {
+            Some(sort) => sort,
+            None if self.q.is_some() => SortField::Relevance,
+            None if self.lat.is_some() => SortField::Distance,
+            None => SortField::CreatedAt,
+        }
+    }
+
+    fn order(&self) -> SortOrder {
+        match (self.order, self.sort()) {
+            (Some(order), _) => order,
+            (None, SortField::Distance) => SortOrder::Asc,
+            (None, _) => SortOrder::Desc,
+        }
+    }
+
+    fn criteria(&self) -> Result<Criteria, ApiError> {
+        self.validate()?;
+
+        let center = Point::from_parts(self.lat, self.lng)?;
+        if center.is_none() && (self.radius_km.is_some() || self.sort == Some(SortField::Distance))
+        {
+            return Err(ApiError::ValidationError(
+                "radius_km and sorting by distance need lat and lng".to_string(),
+            ));
+        }
+        if self
+            .radius_km
+            .is_some_and(|radius_km| !radius_km.is_finite() || radius_km <= 0.0)
+        {
+            return Err(ApiError::ValidationError(
+                "radius_km must be positive".to_string(),
+            ));
+        }
+
+        Ok(Criteria {
+            fts_match: self.match_expression()?,
+            center,
+            radius_km: self.radius_km,
+            bbox: self.bbox.as_deref().map(str::parse).transpose()?,
+        })
+    }
+
+    // Every word of `q` is matched as a prefix, so "balc" finds "balcony".
+    // Words are OR-ed and bm25 ranks listings matching more of them first.
+    fn match_expression(&self) -> Result<Option<String>, ApiError> {
+        let Some(q) = &self.q else {
+            return Ok(None);
+        };
+
+        let terms: Vec<String> = q
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|term| !term.is_empty())
+            .take(MAX_TERMS)
+            .map(|term| format!("\"{term}\"*"))
+            .collect();
+        if terms.is_empty() {
+            return Err(ApiError::ValidationError(
+                "q must contain at least one word".to_string(),
+            ));
+        }
+        Ok(Some(terms.join(" OR ")))
+    }
+
+    fn validate(&self) -> Result<(), ApiError> {
+        fn range<T: PartialOrd>(
+            min: Option<T>,
+            max: Option<T>,
+            name: &str,
+        ) -> Result<(), ApiError> {
+            match (min, max) {
+                (Some(min), Some(max)) if min > max => Err(ApiError::ValidationError(format!(
+                    "min_{name} can't be greater than max_{name}"
+                ))),
+                _ => Ok(()),
+            }
+        }
+
+        range(self.min_price, self.max_price, "price")?;
+        range(self.min_square_feet, self.max_square_feet, "square_feet")?;
+        if self.sort == Some(SortField::Relevance) && self.q.is_none() {
+            return Err(ApiError::ValidationError(
+                "Sorting by relevance needs q".to_string(),
+            ));
+        }
+        if let Some(limit) = self.limit {
+            if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+                return Err(ApiError::ValidationError(format!(
+                    "limit must be between 1 and {MAX_PAGE_SIZE}"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // Text matches are joined in with their score and snippet, and rows get
+    // their haversine from the center. The filters then apply as usual.
+    fn push_source<'a>(&'a self, query: &mut QueryBuilder<'a, Sqlite>, criteria: &Criteria) {
+        match criteria.center {
+            Some(center) => {
+                // With unit vectors, the haversine of the central angle is
+                // (1 - cos θ) / 2 and cos θ is their dot product
+                let [x, y, z] = center.unit_vector();
+                query
+                    .push(" FROM (SELECT *, (1 - (geo_x * ")
+                    .push_bind(x)
+                    .push(" + geo_y * ")
+                    .push_bind(y)
+                    .push(" + geo_z * ")
+                    .push_bind(z)
+                    .push(")) / 2 AS haversine FROM properties) properties");
+            }
+            None => {
+                query.push(" FROM properties");
+            }
+        }
+        if let Some(fts_match) = criteria.fts_match.clone() {
+            query
+                    .push(format!(
+                        " JOIN (
+                            SELECT rowid,
+                                   bm25(properties_fts, 5.0, 1.0, 2.0) AS score,
+                                   snippet(properties_fts, -1, char({}), char({}), '…', 16) AS snippet
+                            FROM properties_fts
+                            WHERE properties_fts MATCH ",
+                        MATCH_START as u32,
+                        MATCH_END as u32
+                    ))
+                    .push_bind(fts_match)
+                    .push(") fts ON fts.rowid = properties.id");
+        }
+        query.push(" WHERE properties.deleted_at IS NULL");
+        self.push_filters(query);
+
+        if let Some(bbox) = criteria.bbox {
+            push_bounding_box(query, bbox);
+        }
+        if let Some(center) = criteria.center {
+            match criteria.radius_km {
+                Some(radius_km) => {
+                    push_bounding_box(query, center.bounding_box(radius_km));
+                    query
+                        .push(" AND haversine <= ")
+                        .push_bind(geo::haversine_of_distance(radius_km));
+                }
+                None => {
+                    query.push(" AND haversine IS NOT NULL");
+                }
+            }
+        }
+    }
+
+    fn push_filters<'a>(&'a self, query: &mut QueryBuilder<'a, Sqlite>) {
+        if let Some(min_price) = self.min_price {
+            query.push(" AND price >= ").push_bind(min_price);
+        }
+        if let Some(max_price) = self.max_price {
+            query.push(" AND price <= ").push_bind(max_price);
+        }
+        if let Some(min_bedrooms) = self.min_bedrooms {
+            query.push(" AND bedrooms >= ").push_bind(min_bedrooms);
+        }
+        if let Some(min_bathrooms) = self.min_bathrooms {
+            query.push(" AND bathrooms >= ").push_bind(min_bathrooms);
+        }
+        if let Some(min_square_feet) = self.min_square_feet {
+            query
+                .push(" AND square_feet >= ")
+                .push_bind(min_square_feet);
+        }
+        if let Some(max_square_feet) = self.max_square_feet {
+            query
+                .push(" AND square_feet <= ")
+                .push_bind(max_square_feet);
+        }
+        if let Some(property_type) = &self.property_type {
+            query.push(" AND property_type = ").push_bind(property_type);
+        }
+        if let Some(listing_type) = &self.listing_type {
+            query.push(" AND listing_type = ").push_bind(listing_type);
+        }
+        if let Some(status) = &self.status {
+            query.push(" AND status = ").push_bind(status);
+        }
+        if let Some(location) = &self.location {
+            let pattern = format!(
+                "%{}%",
+                location
+                    .replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_")
+            );
+            query
+                .push(" AND location LIKE ")
+                .push_bind(pattern)
+                .push(" ESCAPE '\\'");
+        }
+        if let Some(owner_id) = self.owner_id {
+            query.push(" AND owner_id = ").push_bind(owner_id);
+        }
+        if let Some(agent_id) = self.agent_id {
+            query.push(" AND agent_id = ").push_bind(agent_id);
+        }
+    }
+}
+
+pub async fn search(pool: &SqlitePool, search: &PropertySearch) -> Result<PropertyPage, ApiError> {
+    let criteria = search.criteria()?;
+    let sort = search.sort();
+    let order = search.order();
+    let cursor = search.cursor.as_deref().map(Cursor::decode).transpose()?;
+    if let Some(cursor) = &cursor {
+        if cursor.sort != sort || cursor.order != order {
+            return Err(ApiError::ValidationError(
+                "Cursor does not match the requested sort".to_string(),
+            ));
+        }
+    }
+
+    let mut count = QueryBuilder::new("SELECT COUNT(*)");
+    search.push_source(&mut count, &criteria);
+    let total: i64 = count
+        .build_query_scalar()
+        .fetch_one(pool)
+        .await
+        .map_err(ApiError::DatabaseError)?;
+
+    let expression = sort.expression();
+    let (comparison, direction) = match order {
+        SortOrder::Asc => (">", "ASC"),
+        SortOrder::Desc => ("<", "DESC"),
+    };
+
+    let text_columns = match criteria.fts_match {
+        Some(_) => "fts.score, fts.snippet",
+        None => "NULL AS score, NULL AS snippet",
+    };
+    let geo_columns = match criteria.center {
+        Some(_) => "haversine",
+        None => "NULL AS haversine",
+    };
+    let mut query = QueryBuilder::new(format!("SELECT {COLUMNS}, {text_columns}, {geo_columns}"));
+    search.push_source(&mut query, &criteria);
+    if let Some(cursor) = &cursor {
+        // Ties on the sort value are broken by id
+        query.push(format!(" AND ({expression}, id) {comparison} ("));
+        match &cursor.value {
+            Value::String(value) => query.push_bind(value.clone()),
+            value => query.push_bind(value.as_f64()),
+        };
+        query.push(", ").push_bind(cursor.id).push(")");
+    }
+
+    // One extra row tells whether there is a next page
+    let limit = search.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    query
+        .push(format!(
+            " ORDER BY {expression} {direction}, id {direction} LIMIT "
+        ))
+        .push_bind(limit + 1);
+
+    let mut properties: Vec<PropertyHit> = query
+        .build_query_as()
+        .fetch_all(pool)
+        .await
+        .map_err(ApiError::DatabaseError)?;
+
+    let next_cursor = if properties.len() as i64 > limit {
+        properties.truncate(limit as usize);
+        properties.last().map(|last| {
+            Cursor {
+                sort,
+                order,
+                value: sort.value(last),
+                id: last.property.id.unwrap_or_default(),
+            }
+            .encode()
+        })
+    } else {
+        None
+    };
+
+    for hit in &mut properties {
+        hit.snippet = hit.snippet.as_deref().map(highlight);
+        hit.distance_km = hit.haversine.map(geo::distance_from_haversine);
+    }
+
+    Ok(PropertyPage {
+        properties,
+        total,
+        next_cursor,
+    })
+}
+
+// Every match inside the map's bbox, one row per grid cell while zoomed out
+// and one per listing after that
+pub async fn map_cells(
+    pool: &SqlitePool,
+    search: &PropertySearch,
+    zoom: u8,
+) -> Result<Vec<MapCell>, ApiError> {
+    if search.sort.is_some()
+        || search.order.is_some()
+        || search.limit.is_some()
+        || search.cursor.is_some()
+    {
+        return Err(ApiError::ValidationError(
+            "sort, order, limit and cursor don't apply to the map".to_string(),
+        ));
+    }
+    let criteria = search.criteria()?;
+    let bbox = criteria
+        .bbox
+        .ok_or_else(|| ApiError::ValidationError("bbox is required".to_string()))?;
+    let grid = Grid::new(&bbox, zoom)?;
+
+    let clustered = zoom <= property_map::CLUSTER_MAX_ZOOM;
+    let mut query = QueryBuilder::new("");
+    if !clustered {
+        query.push(
+            "SELECT 1 AS count, price AS min_price, price AS max_price, latitude, longitude, \
+             properties.id AS id, title, property_type, listing_type, status",
+        );
+        search.push_source(&mut query, &criteria);
+        query
+            .push(" ORDER BY properties.id LIMIT ")
+            .push_bind(MAX_MAP_LISTINGS + 1);
+    } else {
+        // Columns are linear in longitude, rows are looked up by their edges.
+        // The bare listing columns are only read for cells of one listing.
+        query
+            .push("SELECT CAST((longitude + 180) / ")
+            .push_bind(grid.cell_degrees)
+            .push(" AS INTEGER) AS cell_x, CASE");
+        for (row, edge) in (grid.first_row..).zip(&grid.row_edges) {
+            query
+                .push(" WHEN latitude >= ")
+                .push_bind(*edge)
+                .push(format!(" THEN {row}"));
+        }
+        query.push(format!(
+            " ELSE {} END AS cell_y, \
+             COUNT(*) AS count, MIN(price) AS min_price, MAX(price) AS max_price, \
+             AVG(latitude) AS latitude, AVG(longitude) AS longitude, \
+             properties.id AS id, title, property_type, listing_type, status",
+            grid.first_row + grid.row_edges.len() as i64
+        ));
+        search.push_source(&mut query, &criteria);
+        query.push(" GROUP BY cell_y, cell_x ORDER BY cell_y, cell_x");
+    }
+
+    let cells: Vec<MapCell> = query
+        .build_query_as()
+        .fetch_all(pool)
+        .await
+        .map_err(ApiError::DatabaseError)?;
+    if !clustered && cells.len() as i64 > MAX_MAP_LISTINGS {
+        return Err(ApiError::ValidationError(
+            "Too many listings in view, zoom in or narrow the filters".to_string(),
+        ));
+    }
+
+    Ok(cells)
+}
+
+// The markers must only ever come from snippet(), so listing text may not
+// contain them. Checked before title, description and location are stored.
+pub fn reject_markers(property: &Property) -> Result<(), ApiError> {
+    let fields = [
+        ("title", Some(&property.title)),
+        ("description", property.description.as_ref()),
+        ("location", Some(&property.location)),
+    ];
+    for (name, text) in fields {
+        if text.is_some_and(|text| text.contains([MATCH_START, MATCH_END])) {
+            return Err(ApiError::ValidationError(format!(
+                "{name} contains the reserved characters U+E000 or U+E001"
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Escapes the listing text so the snippet is safe to render as HTML, then
+// marks the matched words with <mark>
+fn highlight(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            MATCH_START => html.push_str("<mark>"),
+            MATCH_END => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+fn push_bounding_box(query: &mut QueryBuilder<'_, Sqlite>, bbox: BoundingBox) {
+    query
+        .push(" AND latitude BETWEEN ")
+        .push_bind(bbox.min_latitude)
+        .push(" AND ")
+        .push_bind(bbox.max_latitude);
+    if bbox.crosses_antimeridian() {
+        query
+            .push(" AND (longitude >= ")
+            .push_bind(bbox.min_longitude)
+            .push(" OR longitude <= ")
+            .push_bind(bbox.max_longitude)
+            .push(")");
+    } else {
+        query
+            .push(" AND longitude BETWEEN ")
+            .push_bind(bbox.min_longitude)
+            .push(" AND ")
+            .push_bind(bbox.max_longitude);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trips() {
+        let cursor = Cursor {
+            sort: SortField::Price,
+            order: SortOrder::Desc,
+            value: Value::from(250000.0),
+            id: 42,
+        };
+        let decoded = Cursor::decode(&cursor.encode()).unwrap();
+        assert_eq!(decoded.sort, SortField::Price);
+        assert_eq!(decoded.order, SortOrder::Desc);
+        assert_eq!(decoded.value, Value::from(250000.0));
+        assert_eq!(decoded.id, 42);
+    }
+
+    #[test]
+    fn cursor_rejects_garbage() {
+        let not_a_cursor = URL_SAFE_NO_PAD.encode(br#"{"id":1}"#);
+        for cursor in ["", "not base64!", not_a_cursor.as_str()] {
+            assert!(
+                matches!(Cursor::decode(cursor), Err(ApiError::ValidationError(_))),
+                "{cursor:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn highlight_escapes_text_and_marks_matches() {
+        let snippet = format!("<b>Tom & Jerry's</b> {MATCH_START}\"loft\"{MATCH_END}");
+        assert_eq!(
+            highlight(&snippet),
+            "&lt;b&gt;Tom &amp; Jerry&#39;s&lt;/b&gt; <mark>&quot;loft&quot;</mark>"
+        );
+    }
+
+    #[test]
+    fn reject_markers_refuses_them_in_listing_text() {
+        let mut property: Property = serde_json::from_value(serde_json::json!({
+            "id": null,
+            "title": "Loft",
+            "price": 1.0,
+            "description": null,
+            "location": "Berlin",
+            "bedrooms": null,
+            "bathrooms": null,
+            "square_feet": null,
+            "property_type": "apartment",
+            "listing_type": "rent",
+            "status": "active",
+            "agent_id": null,
+            "created_at": null,
+            "updated_at": null
+        }))
+        .unwrap();
+        assert!(reject_markers(&property).is_ok());
+
+        property.description = Some(format!("a{MATCH_END}b"));
+        assert!(matches!(
+            reject_markers(&property),
+            Err(ApiError::ValidationError(message)) if message.starts_with("description")
+        ));
+
+        property.description = None;
+        property.title = format!("{MATCH_START}Loft");
+        assert!(reject_markers(&property).is_err());
+    }
+}
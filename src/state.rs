@@ -0,0 +1,24 @@
+// Shared application state handed to every handler
+
+use std::sync::Arc;
+
+use axum::extract::FromRef;
+use sqlx::SqlitePool;
+
+use crate::config::Config;
+use crate::keyring::KeyRing;
+use crate::login_attempts::AttemptStore;
+use crate::mail::MailQueue;
+use crate::oidc::OidcClient;
+use crate::verification::VerificationSender;
+
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    pub pool: SqlitePool,
+    pub keys: Arc<KeyRing>,
+    pub config: Arc<Config>,
+    pub mail: MailQueue,
+    pub verification: Arc<dyn VerificationSender>,
+    pub login_attempts: Arc<dyn AttemptStore>,
+    pub oidc: Arc<OidcClient>,
+}
@@ -0,0 +1,13 @@
+// Opaque one-time tokens (verification links, reset links, ...)
+
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+pub fn new_token() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+// Only this hash is stored, so a leaked table does not leak usable tokens
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
@@ -0,0 +1,114 @@
+// Email address verification
+
+use axum::async_trait;
+use sqlx::{SqliteExecutor, SqlitePool};
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::mail::{MailQueue, Template};
+use crate::tokens::{hash_token, new_token};
+
+// How verification links reach the user
+#[async_trait]
+pub trait VerificationSender: Send + Sync {
+    async fn send(&self, email: &str, link: &str) -> anyhow::Result<()>;
+}
+
+#[async_trait]
+impl VerificationSender for MailQueue {
+    async fn send(&self, email: &str, link: &str) -> anyhow::Result<()> {
+        let link = link.to_string();
+        self.enqueue(email, Template::Verification { link }).await?;
+        Ok(())
+    }
+}
+
+// Actions that can require a verified email address, configured per role
+#[derive(Debug, Clone, Copy)]
+pub enum GatedAction {
+    CreateListing,
+    SendMessage,
+}
+
+pub async fn send_verification(
+    pool: &SqlitePool,
+    config: &Config,
+    sender: &dyn VerificationSender,
+    user_id: i64,
+    email: &str,
+) -> Result<(), ApiError> {
+    let token = issue_verification(pool, user_id).await?;
+    deliver_verification(config, sender, email, &token).await;
+
+    Ok(())
+}
+
+// Stores a new link and returns its token. Deliver it once the caller's
+// transaction is committed.
+pub async fn issue_verification(
+    executor: impl SqliteExecutor<'_>,
+    user_id: i64,
+) -> Result<String, ApiError> {
+    let token = new_token();
+    let token_hash = hash_token(&token);
+
+    sqlx::query!(
+        r#"
+        INSERT INTO email_verifications (user_id, token_hash, expires_at)
+        VALUES (?, ?, datetime('now', '+24 hours'))
+        "#,
+        user_id,
+        token_hash
+    )
+    .execute(executor)
+    .await
+    .map_err(ApiError::DatabaseError)?;
+
+    Ok(token)
+}
+
+// The user can always ask for a new link, so a delivery failure is not fatal
+pub async fn deliver_verification(
+    config: &Config,
+    sender: &dyn VerificationSender,
+    email: &str,
+    token: &str,
+) {
+    let link = format!("{}/verify-email?token={}", config.app_url, token);
+    if let Err(err) = sender.send(email, &link).await {
+        tracing::error!("failed to send verification link to {}: {}", email, err);
+    }
+}
+
+pub async fn require_verified(
+    pool: &SqlitePool,
+    config: &Config,
+    user_id: i64,
+    action: GatedAction,
+) -> Result<(), ApiError> {
+    let user = sqlx::query!(
+        r#"
+        SELECT role, verified
+        FROM users
+        WHERE id = ?
+        "#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(ApiError::DatabaseError)?
+    .ok_or(ApiError::NotFound)?;
+
+    let gated_roles = match action {
+        GatedAction::CreateListing => &config.verified_roles_for_listings,
+        GatedAction::SendMessage => &config.verified_roles_for_messaging,
+    };
+
+    if gated_roles.contains(&user.role) && user.verified != Some(true) {
+        return Err(ApiError::AuthorizationError(
+            "Email address must be verified first".to_string(),
+        ));
+    }
+
+    Ok(())
+}